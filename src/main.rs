@@ -2,25 +2,26 @@
 
 #![deny(warnings)]
 
-use clap::{Arg, ArgAction, ArgMatches, Command, value_parser};
+use clap::{Arg, ArgAction, ArgGroup, ArgMatches, Command, value_parser};
 use clap::builder::PossibleValuesParser;
 use csv::StringRecord;
 use either::{Left, Right};
 use encoding::{DecoderTrap, Encoding};
 use encoding::all::WINDOWS_1251;
-use esl::{CodePage, ALCH, ALDT, ENAM, Field, FileMetadata, FileType, HEDR, NAME, Record, RecordFlags, TES3};
-use esl::{EffectIndex, RecordSerde};
+use esl::{CodePage, AADT, ALCH, ALDT, APPA, ENAM, FNAM, Field, FileMetadata, FileType, HEDR, NAME, Record, RecordFlags, TES3};
+use esl::{ApparatusType, DATA, EffectIndex, FLTV, GMST, INDX, INTV, INGR, IRDT, MAST, MEDT, MGEF, RecordSerde, STRV, Tag};
 use esl::code::{self};
 use esl::read::{RecordReadMode, Records};
 use filetime::{FileTime, set_file_mtime};
 use ini::Ini;
+use regex::{Regex, RegexBuilder};
 use serde_serialize_seed::{ValueWithSeed, VecSerde};
-use std::collections::HashMap;
-use std::env::current_exe;
+use std::any::type_name;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::env::{self, current_exe};
 use std::ffi::OsString;
 use std::fs::{self, File};
-use std::io::{self, BufWriter, Read, BufReader, BufRead};
-use std::mem::transmute;
+use std::io::{self, BufWriter, Read, BufReader, BufRead, Write};
 use std::path::{Path, PathBuf};
 use std::process::ExitCode;
 use std::str::{self, FromStr};
@@ -55,8 +56,9 @@ fn main() -> ExitCode {
         .subcommand(Command::new("scan")
             .about("Scan game config and build .esp file with all potions")
             .before_help("\
-                Scan <CONFIG FILE> for enabled plugins and build <OUTPUT.esp> file \
-                with all potions (without additional modifications)\
+                Scan <CONFIG FILE> (or plugins in --data folders) for enabled plugins \
+                and build <OUTPUT.esp> file with all potions (without additional modifications); \
+                if neither is specified, the game config is searched in default locations\
             ")
             .help_template("Usage: {usage}\n\n{before-help}{options}")
             .arg(Arg::new("help")
@@ -66,10 +68,49 @@ fn main() -> ExitCode {
                 .action(ArgAction::Help)
             )
             .arg(Arg::new("CONFIG FILE")
-                .required(true)
                 .action(ArgAction::Set)
                 .value_parser(value_parser!(OsString))
             )
+            .arg(Arg::new("config_format")
+                .long("config-format")
+                .value_name("FORMAT")
+                .value_parser(PossibleValuesParser::new([
+                    "ini",
+                    "cfg",
+                ]))
+                .help("config file format: Morrowind.ini or openmw.cfg (detected by content by default)")
+            )
+            .arg(Arg::new("data")
+                .short('d')
+                .long("data")
+                .value_name("DIR")
+                .action(ArgAction::Append)
+                .value_parser(value_parser!(OsString))
+                .requires("plugins")
+                .help("data folder, later ones take priority (use instead of config file)")
+            )
+            .arg(Arg::new("plugin")
+                .long("plugin")
+                .value_name("NAME")
+                .action(ArgAction::Append)
+                .value_parser(value_parser!(OsString))
+                .requires("data")
+                .help("plugin to scan, in load order")
+            )
+            .arg(Arg::new("load_order")
+                .short('l')
+                .long("load-order")
+                .value_name("LOAD ORDER.txt")
+                .value_parser(value_parser!(OsString))
+                .help("file with plugin names, one per line, in load order (MO2 plugins.txt or loadorder.txt); \
+                    lists plugins to scan with --data, sorts Morrowind.ini plugins otherwise")
+            )
+            .group(ArgGroup::new("plugins")
+                .args(["plugin", "load_order"])
+            )
+            .group(ArgGroup::new("game")
+                .args(["CONFIG FILE", "data"])
+            )
             .arg(Arg::new("output")
                 .short('o')
                 .long("output")
@@ -78,6 +119,62 @@ fn main() -> ExitCode {
                 .required(true)
                 .help("output plugin file")
             )
+            .arg(Arg::new("groundcover")
+                .long("groundcover")
+                .action(ArgAction::SetTrue)
+                .help("also scan groundcover plugins")
+            )
+            .arg(Arg::new("code_page")
+                .short('p')
+                .long("code-page")
+                .value_name("LANG")
+                .value_parser(PossibleValuesParser::new([
+                    "en",
+                    "ru",
+                ]))
+                .required(true)
+                .help("the game language")
+            )
+        )
+        .subcommand(Command::new("order")
+            .about("Print resolved load order and potions origins")
+            .before_help("\
+                Scan <CONFIG FILE> for enabled plugins and print the resolved load order \
+                and, for every potion, the plugins defining it with the final winner; \
+                if <CONFIG FILE> is not specified, it is searched in default locations\
+            ")
+            .help_template("Usage: {usage}\n\n{before-help}{options}")
+            .arg(Arg::new("help")
+                .short('h')
+                .long("help")
+                .help("display this help and exit")
+                .action(ArgAction::Help)
+            )
+            .arg(Arg::new("CONFIG FILE")
+                .action(ArgAction::Set)
+                .value_parser(value_parser!(OsString))
+            )
+            .arg(Arg::new("config_format")
+                .long("config-format")
+                .value_name("FORMAT")
+                .value_parser(PossibleValuesParser::new([
+                    "ini",
+                    "cfg",
+                ]))
+                .help("config file format: Morrowind.ini or openmw.cfg (detected by content by default)")
+            )
+            .arg(Arg::new("groundcover")
+                .long("groundcover")
+                .action(ArgAction::SetTrue)
+                .help("include groundcover plugins")
+            )
+            .arg(Arg::new("load_order")
+                .short('l')
+                .long("load-order")
+                .value_name("LOAD ORDER.txt")
+                .value_parser(value_parser!(OsString))
+                .help("load order file (MO2 plugins.txt or loadorder.txt) to sort Morrowind.ini plugins by")
+            )
             .arg(Arg::new("code_page")
                 .short('p')
                 .long("code-page")
@@ -90,6 +187,17 @@ fn main() -> ExitCode {
                 .help("the game language")
             )
         )
+        .subcommand(Command::new("locate")
+            .about("Find game configs in default locations")
+            .before_help("Search default locations for openmw.cfg and Morrowind.ini files and print found ones")
+            .help_template("Usage: {usage}\n\n{before-help}{options}")
+            .arg(Arg::new("help")
+                .short('h')
+                .long("help")
+                .help("display this help and exit")
+                .action(ArgAction::Help)
+            )
+        )
         .subcommand(Command::new("init")
             .about("Create .csv file with potions attributes info")
             .before_help("Create <OUTPUT.csv> with potions attributes info")
@@ -116,9 +224,47 @@ fn main() -> ExitCode {
                     "original",
                     "recommended",
                 ]))
-                .required(true)
                 .help("selects one of predefined balances")
             )
+            .arg(Arg::new("formulas")
+                .short('f')
+                .long("formulas")
+                .value_name("SOURCE.csv")
+                .value_parser(value_parser!(OsString))
+                .help("expands formulas in the given balance")
+            )
+            .arg(Arg::new("config")
+                .short('c')
+                .long("config")
+                .value_name("CONFIG FILE")
+                .value_parser(value_parser!(OsString))
+                .requires("formulas")
+                .requires("code_page")
+                .help("game config to read magic effects base costs ('base' formula variable) from")
+            )
+            .arg(Arg::new("config_format")
+                .long("config-format")
+                .value_name("FORMAT")
+                .value_parser(PossibleValuesParser::new([
+                    "ini",
+                    "cfg",
+                ]))
+                .help("config file format: Morrowind.ini or openmw.cfg (detected by content by default)")
+            )
+            .arg(Arg::new("code_page")
+                .short('p')
+                .long("code-page")
+                .value_name("LANG")
+                .value_parser(PossibleValuesParser::new([
+                    "en",
+                    "ru",
+                ]))
+                .help("the game language")
+            )
+            .group(ArgGroup::new("balance")
+                .args(["type", "formulas"])
+                .required(true)
+            )
         )
         .subcommand(Command::new("apply")
             .about("Apply .csv file with potions attributes to base .esp file")
@@ -149,6 +295,68 @@ fn main() -> ExitCode {
                 .required(true)
                 .help("source .csv file")
             )
+            .arg(Arg::new("overrides")
+                .short('r')
+                .long("overrides")
+                .value_name("OVERRIDES.csv")
+                .value_parser(value_parser!(OsString))
+                .help("per-potion overrides .csv file")
+            )
+            .arg(Arg::new("config")
+                .short('c')
+                .long("config")
+                .value_name("CONFIG FILE")
+                .value_parser(value_parser!(OsString))
+                .help("game config to read magic effects base costs and original (unbalanced) items from")
+            )
+            .arg(Arg::new("config_format")
+                .long("config-format")
+                .value_name("FORMAT")
+                .value_parser(PossibleValuesParser::new([
+                    "ini",
+                    "cfg",
+                ]))
+                .help("config file format: Morrowind.ini or openmw.cfg (detected by content by default)")
+            )
+            .arg(Arg::new("quality_rules")
+                .short('q')
+                .long("quality-rules")
+                .value_name("RULES.csv")
+                .value_parser(value_parser!(OsString))
+                .help("potion quality detection rules .csv file (replaces built-in _B/_C/_S/_Q/_E ID suffix rules)")
+            )
+            .arg(Arg::new("infer_quality")
+                .long("infer-quality")
+                .action(ArgAction::SetTrue)
+                .help("guess quality of unrecognized potions from their original attributes (requires --config)")
+            )
+            .arg(Arg::new("multi_effect")
+                .short('m')
+                .long("multi-effect")
+                .value_name("RULE")
+                .value_parser(MultiEffect::parse)
+                .help("balance multi-effect potions (RULE: sum, max, or max+FRACTION)")
+            )
+            .arg(Arg::new("auto_calculated")
+                .short('a')
+                .long("auto-calculated")
+                .value_name("MODE")
+                .value_parser(PossibleValuesParser::new([
+                    "skip",
+                    "clear",
+                    "keep",
+                ]))
+                .default_value("skip")
+                .help("how to treat potions with auto-calculated value")
+            )
+            .arg(Arg::new("load_order")
+                .short('l')
+                .long("load-order")
+                .value_name("LOAD ORDER.txt")
+                .value_parser(value_parser!(OsString))
+                .requires("config")
+                .help("load order file (MO2 plugins.txt or loadorder.txt) to sort Morrowind.ini plugins by")
+            )
             .arg(Arg::new("TARGET.esp")
                 .required(true)
                 .action(ArgAction::Set)
@@ -164,6 +372,8 @@ fn main() -> ExitCode {
     }
     if let Err(err) = match args.subcommand() {
         Some(("scan", scan)) => command_scan(scan),
+        Some(("order", order)) => command_order(order),
+        Some(("locate", _)) => command_locate(),
         Some(("init", init)) => command_init(init),
         Some(("apply", apply)) => command_apply(apply),
         Some((c, _)) => panic!("unknown command '{c}'"),
@@ -180,26 +390,104 @@ fn main() -> ExitCode {
 }
 
 fn command_apply(args: &ArgMatches) -> Result<(), String> {
-    let source = Path::new(args.get_one::<OsString>("source").unwrap());
-    let balance = {
-        let mut source = csv::Reader::from_path(source).map_err(|e| e.to_string())?;
-        let source = source.records().map(|x| x.map_err(|e| e.to_string()));
-        Balance::from_csv(source).map_err(|e| e.unwrap_or_else(|| "Invalid .csv file.".into()))?
-    };
     let code_page = match args.get_one::<String>("code_page").unwrap().as_ref() {
         "en" => CodePage::English,
         "ru" => CodePage::Russian,
         _ => unreachable!()
     };
+    let mut base_costs = HashMap::new();
+    let mut originals = None;
+    if let Some(cfg) = args.get_one::<OsString>("config") {
+        let mut items = Items::default();
+        let load_order = args.get_one::<OsString>("load_order").map(Path::new);
+        for file in game_files(Path::new(cfg), config_format(args), load_order, false)?.plugins {
+            collect_base_costs(&file, &mut base_costs, code_page)?;
+            collect_potions(&file, &mut items, code_page, true)?;
+        }
+        originals = Some(items);
+    }
+    let source = Path::new(args.get_one::<OsString>("source").unwrap());
+    let balance = read_balance(source, &base_costs)?;
+    if let Some(reference) = balance.value_reference_effect {
+        if !base_costs.contains_key(&reference) {
+            return Err(format!(
+                "The balance scales value by '{reference}' base cost, \
+                game config with magic effects is required (use --config)."
+            ));
+        }
+    }
+    let overrides = if let Some(overrides) = args.get_one::<OsString>("overrides") {
+        let mut overrides = csv::Reader::from_path(Path::new(overrides)).map_err(|e| e.to_string())?;
+        let headers = overrides.headers().map_err(|e| e.to_string())?.clone();
+        let overrides = overrides.records().map(|x| x.map_err(|e| e.to_string()));
+        potion_overrides_from_csv(&headers, overrides)?
+    } else {
+        HashMap::new()
+    };
+    let mut quality_rules = if let Some(quality_rules) = args.get_one::<OsString>("quality_rules") {
+        let mut quality_rules = csv::Reader::from_path(Path::new(quality_rules)).map_err(|e| e.to_string())?;
+        let headers = quality_rules.headers().map_err(|e| e.to_string())?.clone();
+        let quality_rules = quality_rules.records().map(|x| x.map_err(|e| e.to_string()));
+        QualityRules::from_csv(&headers, quality_rules)?
+    } else {
+        QualityRules::builtin()
+    };
+    quality_rules.infer_from_stats = *args.get_one("infer_quality").unwrap();
+    if quality_rules.infer_from_stats && originals.is_none() {
+        return Err("Quality inference compares original potion attributes, game config is required (use --config).".into());
+    }
+    let multi_effect = args.get_one::<MultiEffect>("multi_effect").copied();
+    let auto_calculated = match args.get_one::<String>("auto_calculated").unwrap().as_ref() {
+        "skip" => AutoCalculated::Skip,
+        "clear" => AutoCalculated::Clear,
+        "keep" => AutoCalculated::Keep,
+        _ => unreachable!()
+    };
     let target = Path::new(args.get_one::<OsString>("TARGET.esp").unwrap());
     let metadata = fs::metadata(target).map_err(|x| x.to_string())?;
     let time = FileTime::from_last_modification_time(&metadata);
-    let mut potions = HashMap::new();
-    collect_potions(target, &mut potions, code_page, false)?;
-    for potion in potions.values_mut() {
-        patch_potion(potion, &balance)?;
+    let mut items = Items::default();
+    collect_potions(target, &mut items, code_page, false)?;
+    let excluded = overrides.iter().filter(|(_, x)| x.exclude).map(|(id, _)| id.clone()).collect::<HashSet<_>>();
+    items.potions.retain(|id, _| !excluded.contains(id));
+    for (id, potion) in items.potions.iter_mut() {
+        let original = originals.as_ref().and_then(|x| x.potions.get(id));
+        patch_potion(potion, original, &balance, &base_costs, &quality_rules, multi_effect, auto_calculated)?;
+        if let Some(potion_override) = overrides.get(id) {
+            override_potion(potion, potion_override);
+        }
     }
-    write_potions(target, potions, time, code_page)
+    if !balance.ingredient_rarities.is_empty() && originals.is_none() {
+        return Err("Ingredient rarity is based on original ingredient values, game config is required (use --config).".into());
+    }
+    for (id, ingredient) in items.ingredients.iter_mut() {
+        let original = originals.as_ref().and_then(|x| x.ingredients.get(id));
+        patch_ingredient(id, ingredient, original, &balance);
+    }
+    if !balance.apparatus.is_empty() && originals.is_none() {
+        if let Some(id) = items.apparatus.keys().find(|id| ApparatusTier::from_id(id).is_none()) {
+            return Err(format!(
+                "'{id}': non-vanilla apparatus tier is based on original apparatus quality, \
+                game config is required (use --config)."
+            ));
+        }
+    }
+    for (id, apparatus) in items.apparatus.iter_mut() {
+        let original = originals.as_ref().and_then(|x| x.apparatus.get(id));
+        patch_apparatus(id, apparatus, original, &balance);
+    }
+    items.game_settings = balance.game_settings.iter().map(|(name, value)| game_setting_record(name, value)).collect();
+    patch_plugin(target, items, &excluded, time, code_page)
+}
+
+fn read_balance(path: &Path, base_costs: &HashMap<EffectIndex, f32>) -> Result<Balance, String> {
+    let mut csv = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_path(path)
+        .map_err(|e| e.to_string())?;
+    let csv = csv.records().map(|x| x.map_err(|e| e.to_string()));
+    Balance::from_csv(csv, base_costs)
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
@@ -211,47 +499,259 @@ enum Quality {
     Exclusive = 4
 }
 
-fn potion_quality_and_effect(
+const QUALITIES: [Quality; 5] = [
+    Quality::Bargain,
+    Quality::Cheap,
+    Quality::Standard,
+    Quality::Quality,
+    Quality::Exclusive,
+];
+
+impl Quality {
+    fn name(self) -> &'static str {
+        match self {
+            Quality::Bargain => "Bargain",
+            Quality::Cheap => "Cheap",
+            Quality::Standard => "Standard",
+            Quality::Quality => "Quality",
+            Quality::Exclusive => "Exclusive",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Quality> {
+        match name {
+            "Bargain" => Some(Quality::Bargain),
+            "Cheap" => Some(Quality::Cheap),
+            "Standard" => Some(Quality::Standard),
+            "Quality" => Some(Quality::Quality),
+            "Exclusive" => Some(Quality::Exclusive),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+enum QualityRuleTarget {
+    Id,
+    Name,
+}
+
+enum QualityPattern {
+    Suffix(String),
+    Prefix(String),
+    Glob(String),
+    Regex(Regex),
+}
+
+impl QualityPattern {
+    fn is_match(&self, text: &str) -> bool {
+        match self {
+            QualityPattern::Suffix(suffix) => text.ends_with(suffix.as_str()),
+            QualityPattern::Prefix(prefix) => text.starts_with(prefix.as_str()),
+            QualityPattern::Glob(glob) => glob_match(glob.as_bytes(), text.as_bytes()),
+            QualityPattern::Regex(regex) => regex.is_match(text),
+        }
+    }
+}
+
+fn glob_match(glob: &[u8], text: &[u8]) -> bool {
+    let (mut g, mut t) = (0, 0);
+    let mut backtrack = None;
+    while t < text.len() {
+        match glob.get(g) {
+            Some(b'*') => {
+                backtrack = Some((g, t));
+                g += 1;
+            },
+            Some(&c) if c == b'?' || c == text[t] => {
+                g += 1;
+                t += 1;
+            },
+            _ => {
+                let Some((star_g, star_t)) = backtrack else { return false; };
+                backtrack = Some((star_g, star_t + 1));
+                g = star_g + 1;
+                t = star_t + 1;
+            },
+        }
+    }
+    glob[g ..].iter().all(|&c| c == b'*')
+}
+
+struct QualityRule {
+    quality: Quality,
+    target: QualityRuleTarget,
+    pattern: QualityPattern,
+}
+
+struct QualityRules {
+    rules: Vec<QualityRule>,
+    infer_from_stats: bool,
+}
+
+impl QualityRules {
+    fn builtin() -> Self {
+        let suffixes = [
+            (Quality::Bargain, "_B"),
+            (Quality::Cheap, "_C"),
+            (Quality::Standard, "_S"),
+            (Quality::Quality, "_Q"),
+            (Quality::Exclusive, "_E"),
+        ];
+        let rules = suffixes.into_iter().flat_map(|(quality, suffix)| [
+            QualityRule { quality, target: QualityRuleTarget::Id, pattern: QualityPattern::Suffix(suffix.into()) },
+            QualityRule { quality, target: QualityRuleTarget::Id, pattern: QualityPattern::Suffix(format!("{suffix}_CHG")) },
+        ]).collect();
+        QualityRules { rules, infer_from_stats: false }
+    }
+
+    fn from_csv(
+        headers: &StringRecord,
+        csv: impl Iterator<Item=Result<StringRecord, String>>
+    ) -> Result<Self, String> {
+        let headers = csv_headers(headers);
+        let mut errors = Vec::new();
+        let mut rules = Vec::new();
+        for row in csv {
+            let row = row?;
+            let line = row.position().map_or(0, |x| x.line());
+            let label = row.get(0).unwrap_or("").trim();
+            if row.iter().all(|x| x.trim().is_empty()) { continue; }
+            let Some(quality) = Quality::from_name(label) else {
+                errors.push(format!("Line {line}: unknown quality '{label}'."));
+                continue;
+            };
+            let row = CsvRow { row: &row, line, label, headers: &headers, variables: FormulaVariables::default() };
+            let target = match row.text("Target") {
+                None | Some("ID") => QualityRuleTarget::Id,
+                Some("Name") => QualityRuleTarget::Name,
+                Some(text) => {
+                    errors.push(format!(
+                        "Line {line}, row '{label}', column 'Target': invalid value '{text}', expected 'ID' or 'Name'."
+                    ));
+                    continue;
+                },
+            };
+            let Some(pattern) = row.text("Pattern") else {
+                errors.push(format!("Line {line}, row '{label}', column 'Pattern': missing value."));
+                continue;
+            };
+            let pattern = match row.text("Kind") {
+                Some("Suffix") => QualityPattern::Suffix(pattern.to_uppercase()),
+                Some("Prefix") => QualityPattern::Prefix(pattern.to_uppercase()),
+                Some("Glob") => QualityPattern::Glob(pattern.to_uppercase()),
+                Some("Regex") => match RegexBuilder::new(pattern).case_insensitive(true).build() {
+                    Ok(regex) => QualityPattern::Regex(regex),
+                    Err(e) => {
+                        errors.push(format!("Line {line}, row '{label}', column 'Pattern': {e}"));
+                        continue;
+                    },
+                },
+                text => {
+                    errors.push(format!(
+                        "Line {line}, row '{label}', column 'Kind': invalid value '{}', \
+                        expected 'Suffix', 'Prefix', 'Glob', or 'Regex'.",
+                        text.unwrap_or("")
+                    ));
+                    continue;
+                },
+            };
+            rules.push(QualityRule { quality, target, pattern });
+        }
+        if !errors.is_empty() {
+            return Err(errors.join("\n"));
+        }
+        Ok(QualityRules { rules, infer_from_stats: false })
+    }
+
+    fn quality(&self, id: &str, name: Option<&str>) -> Option<Quality> {
+        self.rules.iter().find(|rule| match rule.target {
+            QualityRuleTarget::Id => rule.pattern.is_match(id),
+            QualityRuleTarget::Name => name.is_some_and(|name| rule.pattern.is_match(name)),
+        }).map(|rule| rule.quality)
+    }
+}
+
+type QualityAndEffects = (Option<Quality>, Vec<EffectIndex>);
+
+fn potion_quality_and_effects(
     record: &Record,
-) -> Result<Option<(Option<Quality>, EffectIndex)>, String> {
-    let Field::Potion(data) = &record.fields.iter().find(|(tag, _)| *tag == ALDT).unwrap().1 else { panic!() };
-    if data.auto_calculate_value { return Ok(None); }
+    original: Option<&Record>,
+    quality_rules: &QualityRules,
+    multi_effect: bool,
+) -> Result<Option<QualityAndEffects>, String> {
     let id = if let Field::StringZ(ref id) = record.fields.iter().find(|(tag, _)| *tag == NAME).unwrap().1 {
         id.string.to_uppercase()
     } else {
         panic!()
     };
-    let mut effects = record.fields.iter().filter(|(tag, _)| *tag == ENAM);
-    let Some(effect) = effects.next() else { return Ok(None); };
-    if effects.next().is_some() { return Ok(None); }
-    let effect = if let Field::Effect(effect) = &effect.1 {
-        effect.index.right().ok_or_else(|| format!("Invalid potion '{id}'."))?
+    let effects = record.fields.iter().filter(|(tag, _)| *tag == ENAM).map(|(_, effect)| {
+        let Field::Effect(effect) = effect else { panic!() };
+        effect.index.right().ok_or_else(|| format!("Invalid potion '{id}'."))
+    }).collect::<Result<Vec<_>, _>>()?;
+    if effects.is_empty() || effects.len() > 1 && !multi_effect { return Ok(None); }
+    if effects.iter().all(|&effect| effect_attributes(effect).is_none()) {
+        return Ok(Some((None, effects)));
+    }
+    let name = record.fields.iter().find(|(tag, _)| *tag == FNAM).map(|(_, name)| {
+        let Field::StringZ(name) = name else { panic!() };
+        name.string.to_uppercase()
+    });
+    let quality = quality_rules.quality(&id, name.as_deref()).or_else(|| if quality_rules.infer_from_stats {
+        original.and_then(infer_potion_quality)
     } else {
-        panic!()
-    };
-    if effect_kind(effect) == EffectKind::Damage {
-        return Ok(None);
-    }
-    if effect_attributes(effect).is_none() {
-        return Ok(Some((None, effect)));
-    }
-    let quality = if id.ends_with("_B") || id.ends_with("_B_CHG") {
-        Some(Quality::Bargain)
-    } else if id.ends_with("_C") || id.ends_with("_C_CHG") {
-        Some(Quality::Cheap)
-    } else if id.ends_with("_S") || id.ends_with("_S_CHG") {
-        Some(Quality::Standard)
-    } else if id.ends_with("_Q") || id.ends_with("_Q_CHG") {
-        Some(Quality::Quality)
-    } else if id.ends_with("_E") || id.ends_with("_E_CHG") {
-        Some(Quality::Exclusive)
+        None
+    });
+    Ok(Some((quality, effects)))
+}
+
+fn infer_potion_quality(record: &Record) -> Option<Quality> {
+    let Field::Potion(data) = &record.fields.iter().find(|(tag, _)| *tag == ALDT)?.1 else { panic!() };
+    let (effect, effect_data) = record.fields.iter().filter(|(tag, _)| *tag == ENAM).find_map(|(_, effect_data)| {
+        let Field::Effect(effect_data) = effect_data else { panic!() };
+        let effect = effect_data.index.right()?;
+        effect_attributes(effect).map(|_| (effect, effect_data))
+    })?;
+    let distance = |actual: f64, expected: f64| if actual > 0.0 && expected > 0.0 {
+        Some((actual.ln() - expected.ln()).powi(2))
     } else {
         None
     };
-    Ok(Some((quality, effect)))
+    QUALITIES.iter().filter_map(|&quality| {
+        let distances = [
+            potion_value(Some(quality), effect, &ORIGINAL, &HashMap::new()).and_then(|x| distance(data.value as f64, x as f64)),
+            potion_weight(Some(quality), effect, &ORIGINAL).and_then(|x| distance(data.weight as f64, x as f64)),
+            potion_duration(Some(quality), effect, &ORIGINAL)
+                .and_then(|x| distance(effect_data.duration as f64, x as f64)),
+            potion_magnitude(Some(quality), effect, &ORIGINAL)
+                .and_then(|x| distance(effect_data.magnitude_max as f64, x as f64)),
+        ];
+        let mut distances = distances.into_iter().flatten().peekable();
+        distances.peek()?;
+        Some((quality, distances.sum::<f64>()))
+    }).min_by(|a, b| a.1.total_cmp(&b.1)).map(|(quality, _)| quality)
 }
 
-fn potion_value(quality: Option<Quality>, effect: EffectIndex, balance: &Balance) -> Option<u32> {
+fn potion_value(
+    quality: Option<Quality>,
+    effect: EffectIndex,
+    balance: &Balance,
+    base_costs: &HashMap<EffectIndex, f32>
+) -> Option<u32> {
+    if let Some(value) = balance.effect_override(quality, effect, |x| x.value) {
+        return Some(value);
+    }
+    let scale = |value: u32| {
+        let Some(reference) = balance.value_reference_effect else { return value; };
+        let (Some(base_cost), Some(reference_base_cost)) = (base_costs.get(&effect), base_costs.get(&reference)) else {
+            return value;
+        };
+        if *reference_base_cost <= 0.0 { return value; }
+        (value as f32 * base_cost / reference_base_cost).round() as u32
+    };
+    if let Some(harmful) = balance.harmful.as_ref().filter(|_| harmful_effect(effect)) {
+        return Some(scale(*harmful.value.get(quality?)));
+    }
     Some(match (quality, effect) {
         (None, EffectIndex::Mark) => balance.without_quality_value.mark,
         (None, EffectIndex::Recall) => balance.without_quality_value.teleport,
@@ -263,15 +763,21 @@ fn potion_value(quality: Option<Quality>, effect: EffectIndex, balance: &Balance
         (None, EffectIndex::CureBlightDisease) => balance.without_quality_value.cure_blight_disease,
         (None, EffectIndex::Vampirism) => balance.without_quality_value.vampirism,
         (None, _) => return None,
-        (Some(Quality::Bargain), _) => balance.with_quality_value.bargain,
-        (Some(Quality::Cheap), _) => balance.with_quality_value.cheap,
-        (Some(Quality::Standard), _) => balance.with_quality_value.standard,
-        (Some(Quality::Quality), _) => balance.with_quality_value.quality,
-        (Some(Quality::Exclusive), _) => balance.with_quality_value.exclusive,
+        (Some(Quality::Bargain), _) => scale(balance.with_quality_value.bargain),
+        (Some(Quality::Cheap), _) => scale(balance.with_quality_value.cheap),
+        (Some(Quality::Standard), _) => scale(balance.with_quality_value.standard),
+        (Some(Quality::Quality), _) => scale(balance.with_quality_value.quality),
+        (Some(Quality::Exclusive), _) => scale(balance.with_quality_value.exclusive),
     })
 }
 
 fn potion_weight(quality: Option<Quality>, effect: EffectIndex, balance: &Balance) -> Option<f32> {
+    if let Some(weight) = balance.effect_override(quality, effect, |x| x.weight) {
+        return Some(weight);
+    }
+    if let Some(harmful) = balance.harmful.as_ref().filter(|_| harmful_effect(effect)) {
+        return Some(*harmful.weight.get(quality?));
+    }
     Some(match (quality, effect) {
         (None, EffectIndex::Mark) => balance.without_quality_weight.mark,
         (None, EffectIndex::Recall) => balance.without_quality_weight.teleport,
@@ -291,8 +797,18 @@ fn potion_weight(quality: Option<Quality>, effect: EffectIndex, balance: &Balanc
     })
 }
 
-fn potion_duration(quality: Quality, effect: EffectIndex, balance: &Balance) -> Option<i32> {
-    let effect_attributes = effect_attributes(effect).unwrap();
+fn potion_duration(quality: Option<Quality>, effect: EffectIndex, balance: &Balance) -> Option<i32> {
+    if let Some(duration) = balance.effect_override(quality, effect, |x| x.duration) {
+        return Some(duration);
+    }
+    let quality = quality?;
+    let effect_attributes = effect_attributes(effect)?;
+    if let Some(harmful) = balance.harmful.as_ref().filter(|_| harmful_effect(effect)) {
+        return match effect_attributes {
+            EffectAttributes::Magnitude => None,
+            _ => Some(harmful.duration_and_magnitude.get(quality).0),
+        };
+    }
     let restore = effect_kind(effect) == EffectKind::Restore;
     Some(match (quality, effect_attributes, restore) {
         (Quality::Bargain, EffectAttributes::Duration, _) =>
@@ -329,8 +845,18 @@ fn potion_duration(quality: Quality, effect: EffectIndex, balance: &Balance) ->
     })
 }
 
-fn potion_magnitude(quality: Quality, effect: EffectIndex, balance: &Balance) -> Option<i32> {
-    let effect_attributes = effect_attributes(effect).unwrap();
+fn potion_magnitude(quality: Option<Quality>, effect: EffectIndex, balance: &Balance) -> Option<i32> {
+    if let Some(magnitude) = balance.effect_override(quality, effect, |x| x.magnitude) {
+        return Some(magnitude);
+    }
+    let quality = quality?;
+    let effect_attributes = effect_attributes(effect)?;
+    if let Some(harmful) = balance.harmful.as_ref().filter(|_| harmful_effect(effect)) {
+        return match effect_attributes {
+            EffectAttributes::Duration => None,
+            _ => Some(harmful.duration_and_magnitude.get(quality).1),
+        };
+    }
     let restore = effect_kind(effect) == EffectKind::Restore;
     Some(match (quality, effect_attributes, restore) {
         (Quality::Bargain, EffectAttributes::Magnitude, _) =>
@@ -367,35 +893,175 @@ fn potion_magnitude(quality: Quality, effect: EffectIndex, balance: &Balance) ->
     })
 }
 
-fn patch_potion(record: &mut Record, balance: &Balance) -> Result<(), String> {
-    let Some((quality, effect)) = potion_quality_and_effect(record)? else {
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum MultiEffect {
+    Sum,
+    Max,
+    MaxPlus(f32),
+}
+
+impl MultiEffect {
+    fn parse(s: &str) -> Result<MultiEffect, String> {
+        match s {
+            "sum" => Ok(MultiEffect::Sum),
+            "max" => Ok(MultiEffect::Max),
+            s => s.strip_prefix("max+")
+                .and_then(|fraction| fraction.parse().ok())
+                .filter(|fraction: &f32| (0.0 ..= 1.0).contains(fraction))
+                .map(MultiEffect::MaxPlus)
+                .ok_or_else(|| "expected 'sum', 'max', or 'max+FRACTION' with FRACTION from 0 to 1".into()),
+        }
+    }
+
+    fn combine(self, values: impl Iterator<Item=f32>) -> Option<f32> {
+        let (max, sum) = values.fold(None, |acc: Option<(f32, f32)>, x| {
+            Some(acc.map_or((x, x), |(max, sum)| (max.max(x), sum + x)))
+        })?;
+        Some(match self {
+            MultiEffect::Sum => sum,
+            MultiEffect::Max => max,
+            MultiEffect::MaxPlus(fraction) => max + fraction * (sum - max),
+        })
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+enum AutoCalculated {
+    Skip,
+    Clear,
+    Keep,
+}
+
+fn patch_potion(
+    record: &mut Record,
+    original: Option<&Record>,
+    balance: &Balance,
+    base_costs: &HashMap<EffectIndex, f32>,
+    quality_rules: &QualityRules,
+    multi_effect: Option<MultiEffect>,
+    auto_calculated: AutoCalculated,
+) -> Result<(), String> {
+    let Field::Potion(data) = &record.fields.iter().find(|(tag, _)| *tag == ALDT).unwrap().1 else { panic!() };
+    let auto_calculated = if data.auto_calculate_value { Some(auto_calculated) } else { None };
+    if auto_calculated == Some(AutoCalculated::Skip) { return Ok(()); }
+    let Some((quality, effects)) = potion_quality_and_effects(record, original, quality_rules, multi_effect.is_some())? else {
         return Ok(());
     };
-    if let Some(value) = potion_value(quality, effect, balance) {
-        set_potion_value(record, value);
+    if quality.is_none() && effects.len() > 1 && effects.iter().any(|&effect| effect_attributes(effect).is_some()) {
+        return Ok(());
     }
-    if let Some(weight) = potion_weight(quality, effect, balance) {
-        set_potion_weight(record, weight);
+    if balance.harmful.is_none() && effects.iter().any(|&effect| effect_kind(effect) == EffectKind::Damage) {
+        return Ok(());
     }
-    let Some(quality) = quality else { return Ok(()); };
-    if let Some(duration) = potion_duration(quality, effect, balance) {
-        set_potion_duration(record, duration);
+    let effect_quality = |effect| if effect_attributes(effect).is_none() { None } else { quality };
+    let multi_effect = multi_effect.unwrap_or(MultiEffect::Max);
+    let values = effects.iter()
+        .filter_map(|&effect| potion_value(effect_quality(effect), effect, balance, base_costs));
+    if auto_calculated != Some(AutoCalculated::Keep) {
+        if let Some(value) = multi_effect.combine(values.map(|x| x as f32)) {
+            set_potion_value(record, value.round() as u32);
+            if auto_calculated == Some(AutoCalculated::Clear) {
+                clear_potion_auto_calculate_value(record);
+            }
+        }
+    }
+    let weights = effects.iter().filter_map(|&effect| potion_weight(effect_quality(effect), effect, balance));
+    if let Some(weight) = multi_effect.combine(weights) {
+        set_potion_weight(record, weight);
     }
-    if let Some(magnitude) = potion_magnitude(quality, effect, balance) {
-        set_potion_magnitude(record, magnitude);
+    for (index, &effect) in effects.iter().enumerate() {
+        if let Some(duration) = potion_duration(effect_quality(effect), effect, balance) {
+            set_potion_duration(record, index, duration);
+        }
+        if let Some(magnitude) = potion_magnitude(effect_quality(effect), effect, balance) {
+            set_potion_magnitude(record, index, magnitude);
+        }
     }
     Ok(())
 }
 
-fn set_potion_magnitude(record: &mut Record, value: i32) {
-    let data = record.fields.iter_mut().find(|(tag, _)| *tag == ENAM).unwrap();
+struct PotionOverride {
+    exclude: bool,
+    value: Option<u32>,
+    weight: Option<f32>,
+    duration: Option<i32>,
+    magnitude: Option<i32>,
+}
+
+fn potion_overrides_from_csv(
+    headers: &StringRecord,
+    csv: impl Iterator<Item=Result<StringRecord, String>>
+) -> Result<HashMap<String, PotionOverride>, String> {
+    let headers = csv_headers(headers);
+    let mut errors = Vec::new();
+    let mut overrides = HashMap::new();
+    for row in csv {
+        let row = row?;
+        let line = row.position().map_or(0, |x| x.line());
+        let label = row.get(0).unwrap_or("").trim();
+        if row.iter().all(|x| x.trim().is_empty()) { continue; }
+        if label.is_empty() {
+            errors.push(format!("Line {line}: missing potion ID."));
+            continue;
+        }
+        let row = CsvRow { row: &row, line, label, headers: &headers, variables: FormulaVariables::default() };
+        let exclude = match row.text("Exclude") {
+            None => false,
+            Some(text) => match text.parse() {
+                Ok(exclude) => exclude,
+                Err(_) => {
+                    errors.push(format!(
+                        "Line {line}, row '{label}', column 'Exclude': invalid value '{text}', expected 'true' or 'false'."
+                    ));
+                    false
+                },
+            },
+        };
+        let potion_override = PotionOverride {
+            exclude,
+            value: row.optional("Value", &mut errors),
+            weight: row.optional("Weight", &mut errors),
+            duration: row.optional("Duration", &mut errors),
+            magnitude: row.optional("Magnitude", &mut errors),
+        };
+        if overrides.insert(label.to_uppercase(), potion_override).is_some() {
+            errors.push(format!("Line {line}: duplicate override for potion '{label}'."));
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors.join("\n"));
+    }
+    Ok(overrides)
+}
+
+fn override_potion(record: &mut Record, potion_override: &PotionOverride) {
+    if let Some(value) = potion_override.value {
+        let data = record.fields.iter_mut().find(|(tag, _)| *tag == ALDT).unwrap();
+        let Field::Potion(data) = &mut data.1 else { panic!() };
+        data.value = value;
+        data.auto_calculate_value = false;
+    }
+    if let Some(weight) = potion_override.weight {
+        set_potion_weight(record, weight);
+    }
+    if record.fields.iter().all(|(tag, _)| *tag != ENAM) { return; }
+    if let Some(duration) = potion_override.duration {
+        set_potion_duration(record, 0, duration);
+    }
+    if let Some(magnitude) = potion_override.magnitude {
+        set_potion_magnitude(record, 0, magnitude);
+    }
+}
+
+fn set_potion_magnitude(record: &mut Record, index: usize, value: i32) {
+    let data = record.fields.iter_mut().filter(|(tag, _)| *tag == ENAM).nth(index).unwrap();
     let Field::Effect(data) = &mut data.1 else { panic!() };
     data.magnitude_min = value;
     data.magnitude_max = value;
 }
 
-fn set_potion_duration(record: &mut Record, value: i32) {
-    let data = record.fields.iter_mut().find(|(tag, _)| *tag == ENAM).unwrap();
+fn set_potion_duration(record: &mut Record, index: usize, value: i32) {
+    let data = record.fields.iter_mut().filter(|(tag, _)| *tag == ENAM).nth(index).unwrap();
     let Field::Effect(data) = &mut data.1 else { panic!() };
     data.duration = value;
 }
@@ -403,55 +1069,201 @@ fn set_potion_duration(record: &mut Record, value: i32) {
 fn set_potion_value(record: &mut Record, value: u32) {
     let data = record.fields.iter_mut().find(|(tag, _)| *tag == ALDT).unwrap();
     let Field::Potion(data) = &mut data.1 else { panic!() };
-    if data.value != 0 {
+    if data.value != 0 || data.auto_calculate_value {
         data.value = value;
     }
 }
 
+fn clear_potion_auto_calculate_value(record: &mut Record) {
+    let data = record.fields.iter_mut().find(|(tag, _)| *tag == ALDT).unwrap();
+    let Field::Potion(data) = &mut data.1 else { panic!() };
+    data.auto_calculate_value = false;
+}
+
 fn set_potion_weight(record: &mut Record, value: f32) {
     let data = record.fields.iter_mut().find(|(tag, _)| *tag == ALDT).unwrap();
     let Field::Potion(data) = &mut data.1 else { panic!() };
     data.weight = value;
 }
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
-enum EffectKind {
-    Damage,
-    Restore,
-    Other,
+fn patch_ingredient(id: &str, record: &mut Record, original: Option<&Record>, balance: &Balance) {
+    let original_value = original.and_then(|x| x.fields.iter().find(|(tag, _)| *tag == IRDT)).map(|x| {
+        let Field::Ingredient(data) = &x.1 else { panic!() };
+        data.value
+    });
+    let data = record.fields.iter_mut().find(|(tag, _)| *tag == IRDT).unwrap();
+    let Field::Ingredient(data) = &mut data.1 else { panic!() };
+    let (value, weight) = if let Some(ingredient_override) = balance.ingredient_overrides.iter().find(|x| x.id == id) {
+        (ingredient_override.value, ingredient_override.weight)
+    } else if let Some(rarity) = original_value.and_then(|original_value| balance.ingredient_rarities.iter()
+        .find(|x| x.up_to.is_none_or(|up_to| original_value <= up_to))) {
+        (rarity.value, rarity.weight)
+    } else {
+        return;
+    };
+    if let Some(value) = value {
+        data.value = value;
+    }
+    if let Some(weight) = weight {
+        data.weight = weight;
+    }
 }
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
-enum EffectAttributes {
-    Duration,
-    Magnitude,
-    DurationAndMagnitude,
+#[derive(Debug, Clone, PartialEq)]
+enum GameSetting {
+    Float(f32),
+    Int(i32),
+    String(String),
 }
 
-fn effect_kind(effect: EffectIndex) -> EffectKind {
-    match effect {
-        EffectIndex::FireDamage => EffectKind::Damage,
-        EffectIndex::FrostDamage => EffectKind::Damage,
-        EffectIndex::ShockDamage => EffectKind::Damage,
-        EffectIndex::RestoreHealth => EffectKind::Restore,
-        EffectIndex::RestoreSpellPoints => EffectKind::Restore,
-        EffectIndex::RestoreFatigue => EffectKind::Restore,
-        EffectIndex::DamageHealth => EffectKind::Restore,
-        EffectIndex::DamageMagicka => EffectKind::Restore,
-        EffectIndex::DamageFatigue => EffectKind::Restore,
-        _ => EffectKind::Other,
+fn game_setting_record(name: &str, value: &GameSetting) -> Record {
+    let value = match value {
+        GameSetting::Float(value) => (FLTV, Field::F32(*value)),
+        GameSetting::Int(value) => (INTV, Field::I32(*value)),
+        GameSetting::String(value) => (STRV, Field::String(value.clone())),
+    };
+    Record {
+        tag: GMST,
+        flags: RecordFlags::empty(),
+        fields: vec![(NAME, Field::String(name.into())), value]
     }
 }
 
-fn effect_attributes(effect: EffectIndex) -> Option<EffectAttributes> {
-    match effect {
-        EffectIndex::WaterBreathing => Some(EffectAttributes::Duration),
-        EffectIndex::WaterWalking => Some(EffectAttributes::Duration),
-        EffectIndex::Invisibility => Some(EffectAttributes::Duration),
-        EffectIndex::Paralyze => Some(EffectAttributes::Duration),
-        EffectIndex::Silence => Some(EffectAttributes::Duration),
-        EffectIndex::Dispel => Some(EffectAttributes::Magnitude),
-        EffectIndex::Mark => None,
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+enum ApparatusTier {
+    Apprentice,
+    Journeyman,
+    Master,
+    Grandmaster,
+    SecretMaster,
+}
+
+const APPARATUS_TIERS: [ApparatusTier; 5] = [
+    ApparatusTier::Apprentice,
+    ApparatusTier::Journeyman,
+    ApparatusTier::Master,
+    ApparatusTier::Grandmaster,
+    ApparatusTier::SecretMaster,
+];
+
+impl ApparatusTier {
+    fn name(self) -> &'static str {
+        match self {
+            ApparatusTier::Apprentice => "Apprentice",
+            ApparatusTier::Journeyman => "Journeyman",
+            ApparatusTier::Master => "Master",
+            ApparatusTier::Grandmaster => "Grandmaster",
+            ApparatusTier::SecretMaster => "Secret Master",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<ApparatusTier> {
+        APPARATUS_TIERS.into_iter().find(|x| x.name() == name)
+    }
+
+    fn id_prefix(self) -> &'static str {
+        match self {
+            ApparatusTier::Apprentice => "APPARATUS_A_",
+            ApparatusTier::Journeyman => "APPARATUS_J_",
+            ApparatusTier::Master => "APPARATUS_M_",
+            ApparatusTier::Grandmaster => "APPARATUS_G_",
+            ApparatusTier::SecretMaster => "APPARATUS_SM_",
+        }
+    }
+
+    fn original_quality(self) -> f32 {
+        match self {
+            ApparatusTier::Apprentice => 0.5,
+            ApparatusTier::Journeyman => 1.0,
+            ApparatusTier::Master => 1.2,
+            ApparatusTier::Grandmaster => 1.5,
+            ApparatusTier::SecretMaster => 2.0,
+        }
+    }
+
+    fn from_id(id: &str) -> Option<ApparatusTier> {
+        APPARATUS_TIERS.into_iter().find(|x| id.starts_with(x.id_prefix()))
+    }
+
+    fn detect(id: &str, original_quality: Option<f32>) -> Option<ApparatusTier> {
+        ApparatusTier::from_id(id).or_else(|| {
+            let quality = original_quality?;
+            APPARATUS_TIERS.into_iter()
+                .min_by(|a, b| (a.original_quality() - quality).abs().total_cmp(&(b.original_quality() - quality).abs()))
+        })
+    }
+}
+
+fn patch_apparatus(id: &str, record: &mut Record, original: Option<&Record>, balance: &Balance) {
+    let original_quality = original.and_then(|x| x.fields.iter().find(|(tag, _)| *tag == AADT)).map(|x| {
+        let Field::Apparatus(data) = &x.1 else { panic!() };
+        data.quality
+    });
+    let data = record.fields.iter_mut().find(|(tag, _)| *tag == AADT).unwrap();
+    let Field::Apparatus(data) = &mut data.1 else { panic!() };
+    let Some(tier) = ApparatusTier::detect(id, original_quality) else { return; };
+    let Some(apparatus) = balance.apparatus.iter()
+        .find(|x| x.apparatus_type == data.apparatus_type && x.tier == tier) else {
+        return;
+    };
+    if let Some(quality) = apparatus.quality {
+        data.quality = quality;
+    }
+    if let Some(value) = apparatus.value {
+        data.value = value;
+    }
+    if let Some(weight) = apparatus.weight {
+        data.weight = weight;
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+enum EffectKind {
+    Damage,
+    Restore,
+    Other,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+enum EffectAttributes {
+    Duration,
+    Magnitude,
+    DurationAndMagnitude,
+}
+
+fn effect_kind(effect: EffectIndex) -> EffectKind {
+    match effect {
+        EffectIndex::FireDamage => EffectKind::Damage,
+        EffectIndex::FrostDamage => EffectKind::Damage,
+        EffectIndex::ShockDamage => EffectKind::Damage,
+        EffectIndex::RestoreHealth => EffectKind::Restore,
+        EffectIndex::RestoreSpellPoints => EffectKind::Restore,
+        EffectIndex::RestoreFatigue => EffectKind::Restore,
+        EffectIndex::DamageHealth => EffectKind::Restore,
+        EffectIndex::DamageMagicka => EffectKind::Restore,
+        EffectIndex::DamageFatigue => EffectKind::Restore,
+        _ => EffectKind::Other,
+    }
+}
+
+fn harmful_effect(effect: EffectIndex) -> bool {
+    matches!(effect,
+        EffectIndex::FireDamage | EffectIndex::FrostDamage | EffectIndex::ShockDamage | EffectIndex::SunDamage |
+        EffectIndex::DrainAttribute | EffectIndex::DrainHealth | EffectIndex::DrainSpellpoints |
+        EffectIndex::DrainFatigue | EffectIndex::DrainSkill | EffectIndex::DamageAttribute |
+        EffectIndex::DamageSkill | EffectIndex::Poison | EffectIndex::Paralyze | EffectIndex::Silence
+    )
+}
+
+fn effect_attributes(effect: EffectIndex) -> Option<EffectAttributes> {
+    match effect {
+        EffectIndex::WaterBreathing => Some(EffectAttributes::Duration),
+        EffectIndex::WaterWalking => Some(EffectAttributes::Duration),
+        EffectIndex::Invisibility => Some(EffectAttributes::Duration),
+        EffectIndex::Paralyze => Some(EffectAttributes::Duration),
+        EffectIndex::Silence => Some(EffectAttributes::Duration),
+        EffectIndex::Dispel => Some(EffectAttributes::Magnitude),
+        EffectIndex::Mark => None,
         EffectIndex::Recall => None,
         EffectIndex::DivineIntervention => None,
         EffectIndex::AlmsiviIntervention => None,
@@ -502,6 +1314,7 @@ fn effect_attributes(effect: EffectIndex) -> Option<EffectAttributes> {
     }
 }
 
+#[derive(Clone, Default)]
 struct WithQuality<T> {
     bargain: T,
     cheap: T,
@@ -510,6 +1323,7 @@ struct WithQuality<T> {
     exclusive: T,
 }
 
+#[derive(Clone)]
 struct WithoutQuality<T> {
     mark: T,
     teleport: T,
@@ -519,6 +1333,84 @@ struct WithoutQuality<T> {
     vampirism: T,
 }
 
+impl<T> WithQuality<T> {
+    fn get(&self, quality: Quality) -> &T {
+        match quality {
+            Quality::Bargain => &self.bargain,
+            Quality::Cheap => &self.cheap,
+            Quality::Standard => &self.standard,
+            Quality::Quality => &self.quality,
+            Quality::Exclusive => &self.exclusive,
+        }
+    }
+
+    fn get_mut(&mut self, quality: Quality) -> &mut T {
+        match quality {
+            Quality::Bargain => &mut self.bargain,
+            Quality::Cheap => &mut self.cheap,
+            Quality::Standard => &mut self.standard,
+            Quality::Quality => &mut self.quality,
+            Quality::Exclusive => &mut self.exclusive,
+        }
+    }
+}
+
+impl<T> WithoutQuality<T> {
+    fn get_mut(&mut self, name: &str) -> Option<&mut T> {
+        match name {
+            "Mark" => Some(&mut self.mark),
+            "Teleport" => Some(&mut self.teleport),
+            "Cure Poison / Paralyzation" => Some(&mut self.cure_poison_or_paralyzation),
+            "Cure Common Disease" => Some(&mut self.cure_common_disease),
+            "Cure Blight Disease" => Some(&mut self.cure_blight_disease),
+            "Vampirism" => Some(&mut self.vampirism),
+            _ => None
+        }
+    }
+}
+
+#[derive(Clone)]
+struct EffectOverride {
+    effect: EffectIndex,
+    quality: Option<Quality>,
+    value: Option<u32>,
+    weight: Option<f32>,
+    duration: Option<i32>,
+    magnitude: Option<i32>,
+}
+
+#[derive(Clone)]
+struct IngredientRarity {
+    name: String,
+    up_to: Option<u32>,
+    value: Option<u32>,
+    weight: Option<f32>,
+}
+
+#[derive(Clone)]
+struct IngredientOverride {
+    id: String,
+    value: Option<u32>,
+    weight: Option<f32>,
+}
+
+#[derive(Clone)]
+struct ApparatusBalance {
+    apparatus_type: ApparatusType,
+    tier: ApparatusTier,
+    quality: Option<f32>,
+    value: Option<u32>,
+    weight: Option<f32>,
+}
+
+#[derive(Clone, Default)]
+struct Harmful {
+    value: WithQuality<u32>,
+    weight: WithQuality<f32>,
+    duration_and_magnitude: WithQuality<(i32, i32)>,
+}
+
+#[derive(Clone)]
 struct Balance {
     without_quality_value: WithoutQuality<u32>,
     with_quality_value: WithQuality<u32>,
@@ -528,137 +1420,236 @@ struct Balance {
     magnitude_only: WithQuality<i32>,
     restore_duration_and_magnitude: WithQuality<(i32, i32)>,
     others_duration_and_magnitude: WithQuality<(i32, i32)>,
+    harmful: Option<Harmful>,
+    effect_overrides: Vec<EffectOverride>,
+    value_reference_effect: Option<EffectIndex>,
+    ingredient_rarities: Vec<IngredientRarity>,
+    ingredient_overrides: Vec<IngredientOverride>,
+    apparatus: Vec<ApparatusBalance>,
+    game_settings: Vec<(String, GameSetting)>,
 }
 
 impl Balance {
-    fn from_csv(mut csv: impl Iterator<Item=Result<StringRecord, String>>) -> Result<Self, Option<String>> {
-        let mut balance = Balance {
-            without_quality_value: WithoutQuality {
-                mark: 0,
-                teleport: 0,
-                cure_common_disease: 0,
-                cure_blight_disease: 0,
-                cure_poison_or_paralyzation: 0,
-                vampirism: 0,
-            },
-            with_quality_value: WithQuality {
-                bargain: 0,
-                cheap: 0,
-                standard: 0,
-                quality: 0,
-                exclusive: 0,
-            },
-            without_quality_weight: WithoutQuality {
-                mark: 0.0,
-                teleport: 0.0,
-                cure_common_disease: 0.0,
-                cure_blight_disease: 0.0,
-                cure_poison_or_paralyzation: 0.0,
-                vampirism: 0.0,
-            },
-            with_quality_weight: WithQuality {
-                bargain: 0.0,
-                cheap: 0.0,
-                standard: 0.0,
-                quality: 0.0,
-                exclusive: 0.0,
-            },
-            duration_only: WithQuality {
-                bargain: 0,
-                cheap: 0,
-                standard: 0,
-                quality: 0,
-                exclusive: 0,
-            },
-            magnitude_only: WithQuality {
-                bargain: 0,
-                cheap: 0,
-                standard: 0,
-                quality: 0,
-                exclusive: 0,
-            },
-            restore_duration_and_magnitude: WithQuality {
-                bargain: (0, 0),
-                cheap: (0, 0),
-                standard: (0, 0),
-                quality: (0, 0),
-                exclusive: (0, 0),
-            },
-            others_duration_and_magnitude: WithQuality {
-                bargain: (0, 0),
-                cheap: (0, 0),
-                standard: (0, 0),
-                quality: (0, 0),
-                exclusive: (0, 0),
-            },
-        };
-        let row_bargain = csv.next().ok_or(None)?.map_err(Some)?;
-        balance.with_quality_value.bargain = row_bargain.get(1).ok_or(None)?.parse().map_err(|_| None)?;
-        balance.with_quality_weight.bargain = row_bargain.get(2).ok_or(None)?.parse().map_err(|_| None)?;
-        balance.duration_only.bargain = row_bargain.get(3).ok_or(None)?.parse().map_err(|_| None)?;
-        balance.magnitude_only.bargain = row_bargain.get(4).ok_or(None)?.parse().map_err(|_| None)?;
-        balance.restore_duration_and_magnitude.bargain.0 = row_bargain.get(5).ok_or(None)?.parse().map_err(|_| None)?;
-        balance.restore_duration_and_magnitude.bargain.1 = row_bargain.get(6).ok_or(None)?.parse().map_err(|_| None)?;
-        balance.others_duration_and_magnitude.bargain.0 = row_bargain.get(7).ok_or(None)?.parse().map_err(|_| None)?;
-        balance.others_duration_and_magnitude.bargain.1 = row_bargain.get(8).ok_or(None)?.parse().map_err(|_| None)?;
-        let row_cheap = csv.next().ok_or(None)?.map_err(Some)?;
-        balance.with_quality_value.cheap = row_cheap.get(1).ok_or(None)?.parse().map_err(|_| None)?;
-        balance.with_quality_weight.cheap = row_cheap.get(2).ok_or(None)?.parse().map_err(|_| None)?;
-        balance.duration_only.cheap = row_cheap.get(3).ok_or(None)?.parse().map_err(|_| None)?;
-        balance.magnitude_only.cheap = row_cheap.get(4).ok_or(None)?.parse().map_err(|_| None)?;
-        balance.restore_duration_and_magnitude.cheap.0 = row_cheap.get(5).ok_or(None)?.parse().map_err(|_| None)?;
-        balance.restore_duration_and_magnitude.cheap.1 = row_cheap.get(6).ok_or(None)?.parse().map_err(|_| None)?;
-        balance.others_duration_and_magnitude.cheap.0 = row_cheap.get(7).ok_or(None)?.parse().map_err(|_| None)?;
-        balance.others_duration_and_magnitude.cheap.1 = row_cheap.get(8).ok_or(None)?.parse().map_err(|_| None)?;
-        let row_standard = csv.next().ok_or(None)?.map_err(Some)?;
-        balance.with_quality_value.standard = row_standard.get(1).ok_or(None)?.parse().map_err(|_| None)?;
-        balance.with_quality_weight.standard = row_standard.get(2).ok_or(None)?.parse().map_err(|_| None)?;
-        balance.duration_only.standard = row_standard.get(3).ok_or(None)?.parse().map_err(|_| None)?;
-        balance.magnitude_only.standard = row_standard.get(4).ok_or(None)?.parse().map_err(|_| None)?;
-        balance.restore_duration_and_magnitude.standard.0 = row_standard.get(5).ok_or(None)?.parse().map_err(|_| None)?;
-        balance.restore_duration_and_magnitude.standard.1 = row_standard.get(6).ok_or(None)?.parse().map_err(|_| None)?;
-        balance.others_duration_and_magnitude.standard.0 = row_standard.get(7).ok_or(None)?.parse().map_err(|_| None)?;
-        balance.others_duration_and_magnitude.standard.1 = row_standard.get(8).ok_or(None)?.parse().map_err(|_| None)?;
-        let row_quality = csv.next().ok_or(None)?.map_err(Some)?;
-        balance.with_quality_value.quality = row_quality.get(1).ok_or(None)?.parse().map_err(|_| None)?;
-        balance.with_quality_weight.quality = row_quality.get(2).ok_or(None)?.parse().map_err(|_| None)?;
-        balance.duration_only.quality = row_quality.get(3).ok_or(None)?.parse().map_err(|_| None)?;
-        balance.magnitude_only.quality = row_quality.get(4).ok_or(None)?.parse().map_err(|_| None)?;
-        balance.restore_duration_and_magnitude.quality.0 = row_quality.get(5).ok_or(None)?.parse().map_err(|_| None)?;
-        balance.restore_duration_and_magnitude.quality.1 = row_quality.get(6).ok_or(None)?.parse().map_err(|_| None)?;
-        balance.others_duration_and_magnitude.quality.0 = row_quality.get(7).ok_or(None)?.parse().map_err(|_| None)?;
-        balance.others_duration_and_magnitude.quality.1 = row_quality.get(8).ok_or(None)?.parse().map_err(|_| None)?;
-        let row_exclusive = csv.next().ok_or(None)?.map_err(Some)?;
-        balance.with_quality_value.exclusive = row_exclusive.get(1).ok_or(None)?.parse().map_err(|_| None)?;
-        balance.with_quality_weight.exclusive = row_exclusive.get(2).ok_or(None)?.parse().map_err(|_| None)?;
-        balance.duration_only.exclusive = row_exclusive.get(3).ok_or(None)?.parse().map_err(|_| None)?;
-        balance.magnitude_only.exclusive = row_exclusive.get(4).ok_or(None)?.parse().map_err(|_| None)?;
-        balance.restore_duration_and_magnitude.exclusive.0 = row_exclusive.get(5).ok_or(None)?.parse().map_err(|_| None)?;
-        balance.restore_duration_and_magnitude.exclusive.1 = row_exclusive.get(6).ok_or(None)?.parse().map_err(|_| None)?;
-        balance.others_duration_and_magnitude.exclusive.0 = row_exclusive.get(7).ok_or(None)?.parse().map_err(|_| None)?;
-        balance.others_duration_and_magnitude.exclusive.1 = row_exclusive.get(8).ok_or(None)?.parse().map_err(|_| None)?;
-        csv.next().ok_or(None)?.map_err(Some)?;
-        csv.next().ok_or(None)?.map_err(Some)?;
-        let row_mark = csv.next().ok_or(None)?.map_err(Some)?;
-        balance.without_quality_value.mark = row_mark.get(1).ok_or(None)?.parse().map_err(|_| None)?;
-        balance.without_quality_weight.mark = row_mark.get(2).ok_or(None)?.parse().map_err(|_| None)?;
-        let row_teleport = csv.next().ok_or(None)?.map_err(Some)?;
-        balance.without_quality_value.teleport = row_teleport.get(1).ok_or(None)?.parse().map_err(|_| None)?;
-        balance.without_quality_weight.teleport = row_teleport.get(2).ok_or(None)?.parse().map_err(|_| None)?;
-        let row_cure_p_or_p = csv.next().ok_or(None)?.map_err(Some)?;
-        balance.without_quality_value.cure_poison_or_paralyzation =
-            row_cure_p_or_p.get(1).ok_or(None)?.parse().map_err(|_| None)?;
-        balance.without_quality_weight.cure_poison_or_paralyzation =
-            row_cure_p_or_p.get(2).ok_or(None)?.parse().map_err(|_| None)?;
-        let row_cure_c_disease = csv.next().ok_or(None)?.map_err(Some)?;
-        balance.without_quality_value.cure_common_disease = row_cure_c_disease.get(1).ok_or(None)?.parse().map_err(|_| None)?;
-        balance.without_quality_weight.cure_common_disease = row_cure_c_disease.get(2).ok_or(None)?.parse().map_err(|_| None)?;
-        let row_cure_b_disease = csv.next().ok_or(None)?.map_err(Some)?;
-        balance.without_quality_value.cure_blight_disease = row_cure_b_disease.get(1).ok_or(None)?.parse().map_err(|_| None)?;
-        balance.without_quality_weight.cure_blight_disease = row_cure_b_disease.get(2).ok_or(None)?.parse().map_err(|_| None)?;
-        let row_vampirism = csv.next().ok_or(None)?.map_err(Some)?;
-        balance.without_quality_value.vampirism = row_vampirism.get(1).ok_or(None)?.parse().map_err(|_| None)?;
-        balance.without_quality_weight.vampirism = row_vampirism.get(2).ok_or(None)?.parse().map_err(|_| None)?;
+    fn effect_override<T>(
+        &self,
+        quality: Option<Quality>,
+        effect: EffectIndex,
+        attribute: impl Fn(&EffectOverride) -> Option<T>
+    ) -> Option<T> {
+        let overrides = || self.effect_overrides.iter().filter(|x| x.effect == effect);
+        overrides().filter(|x| quality.is_some() && x.quality == quality).find_map(&attribute)
+            .or_else(|| overrides().filter(|x| x.quality.is_none()).find_map(&attribute))
+    }
+
+    fn from_csv(
+        csv: impl Iterator<Item=Result<StringRecord, String>>,
+        base_costs: &HashMap<EffectIndex, f32>
+    ) -> Result<Self, String> {
+        let mut balance = ORIGINAL.clone();
+        let mut errors = Vec::new();
+        let mut headers: Option<HashMap<String, usize>> = None;
+        let mut with_quality_rows = HashSet::new();
+        let mut harmful_rows = HashSet::new();
+        let mut without_quality_rows = HashSet::new();
+        let mut effect_override_rows = HashSet::new();
+        let mut setting_rows = HashSet::new();
+        let mut ingredient_rarity_rows = HashSet::new();
+        let mut ingredient_override_rows = HashSet::new();
+        let mut apparatus_rows = HashSet::new();
+        let mut game_setting_rows = HashSet::new();
+        for row in csv {
+            let row = row?;
+            let line = row.position().map_or(0, |x| x.line());
+            let label = row.get(0).unwrap_or("").trim();
+            if label.is_empty() {
+                if row.iter().any(|x| !x.trim().is_empty()) {
+                    headers = Some(csv_headers(&row));
+                }
+                continue;
+            }
+            let Some(headers) = headers.as_ref() else {
+                errors.push(format!("Line {line}: missing header before row '{label}'."));
+                continue;
+            };
+            let row = CsvRow { row: &row, line, label, headers, variables: FormulaVariables::default() };
+            if headers.contains_key("Setting") {
+                if !setting_rows.insert(label.to_string()) {
+                    errors.push(format!("Line {line}: duplicate row '{label}'."));
+                    continue;
+                }
+                match label {
+                    "Value Reference Effect" => {
+                        let Some(text) = row.text("Setting") else { continue; };
+                        let Ok(effect) = text.parse() else {
+                            errors.push(format!(
+                                "Line {line}, row '{label}', column 'Setting': invalid value '{text}', expected effect."
+                            ));
+                            continue;
+                        };
+                        balance.value_reference_effect = Some(effect);
+                    },
+                    _ => errors.push(format!("Line {line}: unknown setting '{label}'.")),
+                }
+            } else if headers.contains_key("GMST Value") {
+                if !game_setting_rows.insert(label.to_lowercase()) {
+                    errors.push(format!("Line {line}: duplicate row '{label}'."));
+                    continue;
+                }
+                let value = match label.chars().next().map(|x| x.to_ascii_lowercase()) {
+                    Some('f') => {
+                        let mut value = 0.0;
+                        row.required("GMST Value", &mut errors, &mut value);
+                        GameSetting::Float(value)
+                    },
+                    Some('i') => {
+                        let mut value = 0;
+                        row.required("GMST Value", &mut errors, &mut value);
+                        GameSetting::Int(value)
+                    },
+                    Some('s') => GameSetting::String(row.text("GMST Value").unwrap_or("").into()),
+                    _ => {
+                        errors.push(format!("Line {line}: GMST '{label}' name should start with 'f', 'i', or 's'."));
+                        continue;
+                    },
+                };
+                balance.game_settings.push((label.to_string(), value));
+            } else if headers.contains_key("Apparatus Tier") {
+                let Ok(apparatus_type) = label.parse() else {
+                    errors.push(format!("Line {line}: unknown apparatus type '{label}'."));
+                    continue;
+                };
+                let Some(tier) = row.text("Apparatus Tier") else {
+                    errors.push(format!("Line {line}, row '{label}', column 'Apparatus Tier': missing value."));
+                    continue;
+                };
+                let Some(tier) = ApparatusTier::from_name(tier) else {
+                    errors.push(format!(
+                        "Line {line}, row '{label}', column 'Apparatus Tier': invalid value '{tier}', expected tier."
+                    ));
+                    continue;
+                };
+                if !apparatus_rows.insert((apparatus_type, tier)) {
+                    errors.push(format!("Line {line}: duplicate row '{label}'."));
+                    continue;
+                }
+                let row = row.with_variables(FormulaVariables { tier: Some(tier as u8 as f64), base: None });
+                balance.apparatus.push(ApparatusBalance {
+                    apparatus_type,
+                    tier,
+                    quality: row.optional("Apparatus Quality", &mut errors),
+                    value: row.optional("Apparatus Value", &mut errors),
+                    weight: row.optional("Apparatus Weight", &mut errors),
+                });
+            } else if headers.contains_key("Original Value Up To") {
+                if !ingredient_rarity_rows.insert(label.to_string()) {
+                    errors.push(format!("Line {line}: duplicate row '{label}'."));
+                    continue;
+                }
+                balance.ingredient_rarities.push(IngredientRarity {
+                    name: label.to_string(),
+                    up_to: row.optional("Original Value Up To", &mut errors),
+                    value: row.optional("Ingredient Value", &mut errors),
+                    weight: row.optional("Ingredient Weight", &mut errors),
+                });
+            } else if headers.contains_key("Ingredient Value") {
+                let id = label.to_uppercase();
+                if !ingredient_override_rows.insert(id.clone()) {
+                    errors.push(format!("Line {line}: duplicate row '{label}'."));
+                    continue;
+                }
+                balance.ingredient_overrides.push(IngredientOverride {
+                    id,
+                    value: row.optional("Ingredient Value", &mut errors),
+                    weight: row.optional("Ingredient Weight", &mut errors),
+                });
+            } else if headers.contains_key("Quality") {
+                let Ok(effect) = label.parse() else {
+                    errors.push(format!("Line {line}: unknown effect '{label}'."));
+                    continue;
+                };
+                let quality = match row.text("Quality") {
+                    None => None,
+                    Some(text) => match Quality::from_name(text) {
+                        Some(quality) => Some(quality),
+                        None => {
+                            errors.push(format!(
+                                "Line {line}, row '{label}', column 'Quality': invalid value '{text}', expected quality."
+                            ));
+                            continue;
+                        },
+                    },
+                };
+                if !effect_override_rows.insert((effect, quality)) {
+                    errors.push(format!("Line {line}: duplicate row '{label}'."));
+                    continue;
+                }
+                let row = row.with_variables(FormulaVariables {
+                    tier: quality.map(|x| x as u8 as f64),
+                    base: base_costs.get(&effect).map(|&x| x as f64),
+                });
+                balance.effect_overrides.push(EffectOverride {
+                    effect,
+                    quality,
+                    value: row.optional("Value", &mut errors),
+                    weight: row.optional("Weight", &mut errors),
+                    duration: row.optional("Duration", &mut errors),
+                    magnitude: row.optional("Magnitude", &mut errors),
+                });
+            } else if headers.contains_key("Harmful Value") {
+                let Some(quality) = Quality::from_name(label) else {
+                    errors.push(format!("Line {line}: unknown quality '{label}'."));
+                    continue;
+                };
+                if !harmful_rows.insert(quality) {
+                    errors.push(format!("Line {line}: duplicate row '{label}'."));
+                    continue;
+                }
+                let row = row.with_variables(FormulaVariables { tier: Some(quality as u8 as f64), base: None });
+                let harmful = balance.harmful.get_or_insert_with(Harmful::default);
+                row.required("Harmful Value", &mut errors, harmful.value.get_mut(quality));
+                row.required("Harmful Weight", &mut errors, harmful.weight.get_mut(quality));
+                let duration_and_magnitude = harmful.duration_and_magnitude.get_mut(quality);
+                row.required("Harmful Duration", &mut errors, &mut duration_and_magnitude.0);
+                row.required("Harmful Magnitude", &mut errors, &mut duration_and_magnitude.1);
+            } else if let Some(quality) = Quality::from_name(label) {
+                if !with_quality_rows.insert(quality) {
+                    errors.push(format!("Line {line}: duplicate row '{label}'."));
+                    continue;
+                }
+                let row = row.with_variables(FormulaVariables { tier: Some(quality as u8 as f64), base: None });
+                row.required("Value", &mut errors, balance.with_quality_value.get_mut(quality));
+                row.required("Weight", &mut errors, balance.with_quality_weight.get_mut(quality));
+                row.required("Duration Only", &mut errors, balance.duration_only.get_mut(quality));
+                row.required("Magnitude Only", &mut errors, balance.magnitude_only.get_mut(quality));
+                let restore = balance.restore_duration_and_magnitude.get_mut(quality);
+                row.required("Restore Duration", &mut errors, &mut restore.0);
+                row.required("Restore Magnitude", &mut errors, &mut restore.1);
+                let others = balance.others_duration_and_magnitude.get_mut(quality);
+                row.required("Others Duration", &mut errors, &mut others.0);
+                row.required("Others Magnitude", &mut errors, &mut others.1);
+            } else if let Some(value) = balance.without_quality_value.get_mut(label) {
+                if !without_quality_rows.insert(label.to_string()) {
+                    errors.push(format!("Line {line}: duplicate row '{label}'."));
+                    continue;
+                }
+                row.required("Value", &mut errors, value);
+                row.required("Weight", &mut errors, balance.without_quality_weight.get_mut(label).unwrap());
+            } else {
+                errors.push(format!("Line {line}: unknown row '{label}'."));
+            }
+        }
+        for quality in QUALITIES.iter().filter(|x| !with_quality_rows.contains(x)) {
+            errors.push(format!("Missing row '{}'.", quality.name()));
+        }
+        if !harmful_rows.is_empty() {
+            for quality in QUALITIES.iter().filter(|x| !harmful_rows.contains(x)) {
+                errors.push(format!("Missing harmful row '{}'.", quality.name()));
+            }
+        }
+        balance.ingredient_rarities.sort_by_key(|x| (x.up_to.is_none(), x.up_to));
+        if !errors.is_empty() {
+            return Err(errors.join("\n"));
+        }
         Ok(balance)
     }
 
@@ -791,10 +1782,410 @@ impl Balance {
             row_vampirism.push_field("");
         }
         rows.push(row_vampirism);
+        if let Some(value_reference_effect) = self.value_reference_effect {
+            let mut row_empty = StringRecord::new();
+            for _ in 0 .. 9 {
+                row_empty.push_field("");
+            }
+            rows.push(row_empty);
+            let mut row_headers = StringRecord::new();
+            row_headers.push_field("");
+            row_headers.push_field("Setting");
+            for _ in 0 .. 7 {
+                row_headers.push_field("");
+            }
+            rows.push(row_headers);
+            let mut row_value_reference_effect = StringRecord::new();
+            row_value_reference_effect.push_field("Value Reference Effect");
+            row_value_reference_effect.push_field(&value_reference_effect.to_string());
+            for _ in 0 .. 7 {
+                row_value_reference_effect.push_field("");
+            }
+            rows.push(row_value_reference_effect);
+        }
+        if let Some(harmful) = &self.harmful {
+            let mut row_empty = StringRecord::new();
+            for _ in 0 .. 9 {
+                row_empty.push_field("");
+            }
+            rows.push(row_empty);
+            let mut row_headers = StringRecord::new();
+            row_headers.push_field("");
+            row_headers.push_field("Harmful Value");
+            row_headers.push_field("Harmful Weight");
+            row_headers.push_field("Harmful Duration");
+            row_headers.push_field("Harmful Magnitude");
+            for _ in 0 .. 4 {
+                row_headers.push_field("");
+            }
+            rows.push(row_headers);
+            for quality in QUALITIES {
+                let mut row_harmful = StringRecord::new();
+                row_harmful.push_field(quality.name());
+                row_harmful.push_field(&harmful.value.get(quality).to_string());
+                row_harmful.push_field(&harmful.weight.get(quality).to_string());
+                row_harmful.push_field(&harmful.duration_and_magnitude.get(quality).0.to_string());
+                row_harmful.push_field(&harmful.duration_and_magnitude.get(quality).1.to_string());
+                for _ in 0 .. 4 {
+                    row_harmful.push_field("");
+                }
+                rows.push(row_harmful);
+            }
+        }
+        if !self.ingredient_rarities.is_empty() {
+            let mut row_empty = StringRecord::new();
+            for _ in 0 .. 9 {
+                row_empty.push_field("");
+            }
+            rows.push(row_empty);
+            let mut row_headers = StringRecord::new();
+            row_headers.push_field("");
+            row_headers.push_field("Original Value Up To");
+            row_headers.push_field("Ingredient Value");
+            row_headers.push_field("Ingredient Weight");
+            for _ in 0 .. 5 {
+                row_headers.push_field("");
+            }
+            rows.push(row_headers);
+            for rarity in &self.ingredient_rarities {
+                let mut row_rarity = StringRecord::new();
+                row_rarity.push_field(&rarity.name);
+                row_rarity.push_field(&rarity.up_to.map_or_else(String::new, |x| x.to_string()));
+                row_rarity.push_field(&rarity.value.map_or_else(String::new, |x| x.to_string()));
+                row_rarity.push_field(&rarity.weight.map_or_else(String::new, |x| x.to_string()));
+                for _ in 0 .. 5 {
+                    row_rarity.push_field("");
+                }
+                rows.push(row_rarity);
+            }
+        }
+        if !self.ingredient_overrides.is_empty() {
+            let mut row_empty = StringRecord::new();
+            for _ in 0 .. 9 {
+                row_empty.push_field("");
+            }
+            rows.push(row_empty);
+            let mut row_headers = StringRecord::new();
+            row_headers.push_field("");
+            row_headers.push_field("Ingredient Value");
+            row_headers.push_field("Ingredient Weight");
+            for _ in 0 .. 6 {
+                row_headers.push_field("");
+            }
+            rows.push(row_headers);
+            for ingredient_override in &self.ingredient_overrides {
+                let mut row_override = StringRecord::new();
+                row_override.push_field(&ingredient_override.id);
+                row_override.push_field(&ingredient_override.value.map_or_else(String::new, |x| x.to_string()));
+                row_override.push_field(&ingredient_override.weight.map_or_else(String::new, |x| x.to_string()));
+                for _ in 0 .. 6 {
+                    row_override.push_field("");
+                }
+                rows.push(row_override);
+            }
+        }
+        if !self.apparatus.is_empty() {
+            let mut row_empty = StringRecord::new();
+            for _ in 0 .. 9 {
+                row_empty.push_field("");
+            }
+            rows.push(row_empty);
+            let mut row_headers = StringRecord::new();
+            row_headers.push_field("");
+            row_headers.push_field("Apparatus Tier");
+            row_headers.push_field("Apparatus Quality");
+            row_headers.push_field("Apparatus Value");
+            row_headers.push_field("Apparatus Weight");
+            for _ in 0 .. 4 {
+                row_headers.push_field("");
+            }
+            rows.push(row_headers);
+            for apparatus in &self.apparatus {
+                let mut row_apparatus = StringRecord::new();
+                row_apparatus.push_field(&apparatus.apparatus_type.to_string());
+                row_apparatus.push_field(apparatus.tier.name());
+                row_apparatus.push_field(&apparatus.quality.map_or_else(String::new, |x| x.to_string()));
+                row_apparatus.push_field(&apparatus.value.map_or_else(String::new, |x| x.to_string()));
+                row_apparatus.push_field(&apparatus.weight.map_or_else(String::new, |x| x.to_string()));
+                for _ in 0 .. 4 {
+                    row_apparatus.push_field("");
+                }
+                rows.push(row_apparatus);
+            }
+        }
+        if !self.game_settings.is_empty() {
+            let mut row_empty = StringRecord::new();
+            for _ in 0 .. 9 {
+                row_empty.push_field("");
+            }
+            rows.push(row_empty);
+            let mut row_headers = StringRecord::new();
+            row_headers.push_field("");
+            row_headers.push_field("GMST Value");
+            for _ in 0 .. 7 {
+                row_headers.push_field("");
+            }
+            rows.push(row_headers);
+            for (name, value) in &self.game_settings {
+                let mut row_game_setting = StringRecord::new();
+                row_game_setting.push_field(name);
+                row_game_setting.push_field(&match value {
+                    GameSetting::Float(value) => value.to_string(),
+                    GameSetting::Int(value) => value.to_string(),
+                    GameSetting::String(value) => value.clone(),
+                });
+                for _ in 0 .. 7 {
+                    row_game_setting.push_field("");
+                }
+                rows.push(row_game_setting);
+            }
+        }
+        if self.effect_overrides.is_empty() { return rows; }
+        let mut row_empty = StringRecord::new();
+        for _ in 0 .. 9 {
+            row_empty.push_field("");
+        }
+        rows.push(row_empty);
+        let mut row_headers = StringRecord::new();
+        row_headers.push_field("");
+        row_headers.push_field("Quality");
+        row_headers.push_field("Value");
+        row_headers.push_field("Weight");
+        row_headers.push_field("Duration");
+        row_headers.push_field("Magnitude");
+        for _ in 0 .. 3 {
+            row_headers.push_field("");
+        }
+        rows.push(row_headers);
+        for effect_override in &self.effect_overrides {
+            let mut row_override = StringRecord::new();
+            row_override.push_field(&effect_override.effect.to_string());
+            row_override.push_field(effect_override.quality.map_or("", |x| x.name()));
+            row_override.push_field(&effect_override.value.map_or_else(String::new, |x| x.to_string()));
+            row_override.push_field(&effect_override.weight.map_or_else(String::new, |x| x.to_string()));
+            row_override.push_field(&effect_override.duration.map_or_else(String::new, |x| x.to_string()));
+            row_override.push_field(&effect_override.magnitude.map_or_else(String::new, |x| x.to_string()));
+            for _ in 0 .. 3 {
+                row_override.push_field("");
+            }
+            rows.push(row_override);
+        }
         rows
     }
 }
 
+trait CsvValue: FromStr {
+    fn from_f64(value: f64) -> Option<Self>;
+}
+
+impl CsvValue for u32 {
+    fn from_f64(value: f64) -> Option<u32> {
+        let value = value.round();
+        if (0.0 ..= u32::MAX as f64).contains(&value) { Some(value as u32) } else { None }
+    }
+}
+
+impl CsvValue for i32 {
+    fn from_f64(value: f64) -> Option<i32> {
+        let value = value.round();
+        if (i32::MIN as f64 ..= i32::MAX as f64).contains(&value) { Some(value as i32) } else { None }
+    }
+}
+
+impl CsvValue for f32 {
+    fn from_f64(value: f64) -> Option<f32> {
+        if value.is_finite() { Some(value as f32) } else { None }
+    }
+}
+
+fn csv_headers(row: &StringRecord) -> HashMap<String, usize> {
+    row.iter().enumerate().skip(1)
+        .filter(|(_, x)| !x.trim().is_empty())
+        .map(|(index, x)| (x.trim().to_string(), index))
+        .collect()
+}
+
+struct CsvRow<'a> {
+    row: &'a StringRecord,
+    line: u64,
+    label: &'a str,
+    headers: &'a HashMap<String, usize>,
+    variables: FormulaVariables,
+}
+
+impl<'a> CsvRow<'a> {
+    fn with_variables(self, variables: FormulaVariables) -> Self {
+        CsvRow { variables, ..self }
+    }
+
+    fn text(&self, column: &str) -> Option<&'a str> {
+        let &index = self.headers.get(column)?;
+        self.row.get(index).map(|x| x.trim()).filter(|x| !x.is_empty())
+    }
+
+    fn parse<T: CsvValue>(&self, column: &str, text: &str, errors: &mut Vec<String>) -> Option<T> {
+        if let Ok(value) = text.parse() { return Some(value); }
+        if text.parse::<f64>().is_ok() {
+            errors.push(format!(
+                "Line {}, row '{}', column '{}': invalid value '{}', expected {}.",
+                self.line, self.label, column, text, type_name::<T>()
+            ));
+            return None;
+        }
+        let value = match eval_formula(text, self.variables) {
+            Ok(value) => value,
+            Err(error) => {
+                errors.push(format!(
+                    "Line {}, row '{}', column '{}': invalid value '{}', expected {} or formula ({}).",
+                    self.line, self.label, column, text, type_name::<T>(), error
+                ));
+                return None;
+            },
+        };
+        let parsed = T::from_f64(value);
+        if parsed.is_none() {
+            errors.push(format!(
+                "Line {}, row '{}', column '{}': formula '{}' evaluates to {}, expected {}.",
+                self.line, self.label, column, text, value, type_name::<T>()
+            ));
+        }
+        parsed
+    }
+
+    fn optional<T: CsvValue>(&self, column: &str, errors: &mut Vec<String>) -> Option<T> {
+        self.text(column).and_then(|text| self.parse(column, text, errors))
+    }
+
+    fn required<T: CsvValue>(&self, column: &str, errors: &mut Vec<String>, value: &mut T) {
+        let Some(text) = self.text(column) else {
+            errors.push(format!("Line {}, row '{}', column '{}': missing value.", self.line, self.label, column));
+            return;
+        };
+        if let Some(parsed) = self.parse(column, text, errors) {
+            *value = parsed;
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+struct FormulaVariables {
+    tier: Option<f64>,
+    base: Option<f64>,
+}
+
+fn eval_formula(formula: &str, variables: FormulaVariables) -> Result<f64, String> {
+    let mut parser = FormulaParser { text: formula.as_bytes(), pos: 0, variables };
+    let value = parser.sum()?;
+    parser.skip_spaces();
+    if parser.pos != parser.text.len() {
+        return Err(parser.error(parser.pos, format!("unexpected '{}'", &formula[parser.pos ..])));
+    }
+    Ok(value)
+}
+
+struct FormulaParser<'a> {
+    text: &'a [u8],
+    pos: usize,
+    variables: FormulaVariables,
+}
+
+impl<'a> FormulaParser<'a> {
+    fn error(&self, pos: usize, message: impl Into<String>) -> String {
+        format!("{} at position {}", message.into(), pos + 1)
+    }
+
+    fn skip_spaces(&mut self) {
+        while self.text.get(self.pos).is_some_and(|x| x.is_ascii_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    fn next_if(&mut self, c: u8) -> bool {
+        self.skip_spaces();
+        if self.text.get(self.pos) == Some(&c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn sum(&mut self) -> Result<f64, String> {
+        let mut value = self.product()?;
+        loop {
+            if self.next_if(b'+') {
+                value += self.product()?;
+            } else if self.next_if(b'-') {
+                value -= self.product()?;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn product(&mut self) -> Result<f64, String> {
+        let mut value = self.power()?;
+        loop {
+            if self.next_if(b'*') {
+                value *= self.power()?;
+            } else if self.next_if(b'/') {
+                value /= self.power()?;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn power(&mut self) -> Result<f64, String> {
+        let value = self.unary()?;
+        if self.next_if(b'^') {
+            Ok(value.powf(self.power()?))
+        } else {
+            Ok(value)
+        }
+    }
+
+    fn unary(&mut self) -> Result<f64, String> {
+        if self.next_if(b'-') {
+            Ok(-self.unary()?)
+        } else {
+            self.primary()
+        }
+    }
+
+    fn primary(&mut self) -> Result<f64, String> {
+        if self.next_if(b'(') {
+            let value = self.sum()?;
+            if !self.next_if(b')') { return Err(self.error(self.pos, "missing ')'")); }
+            return Ok(value);
+        }
+        self.skip_spaces();
+        let start = self.pos;
+        let rest = &self.text[start ..];
+        if rest.first().is_some_and(|x| x.is_ascii_alphabetic()) {
+            let len = rest.iter().take_while(|x| x.is_ascii_alphanumeric() || **x == b'_').count();
+            self.pos += len;
+            let name = str::from_utf8(&rest[.. len]).unwrap();
+            return match name {
+                "tier" => self.variables.tier.ok_or_else(|| {
+                    self.error(start, "'tier' is defined only in rows with a quality or an apparatus tier")
+                }),
+                "base" => self.variables.base.ok_or_else(|| {
+                    self.error(start, "'base' is defined only in effect override rows, \
+                        and requires game config with magic effects (use --config)")
+                }),
+                _ => Err(self.error(start, format!("unknown variable '{name}'"))),
+            };
+        }
+        let len = rest.iter().take_while(|x| x.is_ascii_digit() || **x == b'.').count();
+        if len == 0 {
+            return Err(self.error(start, if rest.is_empty() { "unexpected end" } else { "number expected" }));
+        }
+        self.pos += len;
+        str::from_utf8(&rest[.. len]).unwrap().parse().map_err(|_| self.error(start, "invalid number"))
+    }
+}
+
 static ORIGINAL: Balance = Balance {
     without_quality_value: WithoutQuality {
         mark: 35,
@@ -854,6 +2245,13 @@ static ORIGINAL: Balance = Balance {
         quality: (45, 15),
         exclusive: (60, 20),
     },
+    harmful: None,
+    effect_overrides: Vec::new(),
+    value_reference_effect: None,
+    ingredient_rarities: Vec::new(),
+    ingredient_overrides: Vec::new(),
+    apparatus: Vec::new(),
+    game_settings: Vec::new(),
 };
 
 static RECOMMENDED: Balance = Balance {
@@ -915,13 +2313,59 @@ static RECOMMENDED: Balance = Balance {
         quality: (160, 70),
         exclusive: (320, 100),
     },
+    harmful: Some(Harmful {
+        value: WithQuality {
+            bargain: 20,
+            cheap: 40,
+            standard: 80,
+            quality: 160,
+            exclusive: 320,
+        },
+        weight: WithQuality {
+            bargain: 1.0,
+            cheap: 0.8,
+            standard: 0.6,
+            quality: 0.4,
+            exclusive: 0.2,
+        },
+        duration_and_magnitude: WithQuality {
+            bargain: (5, 5),
+            cheap: (10, 10),
+            standard: (15, 20),
+            quality: (20, 35),
+            exclusive: (30, 50),
+        },
+    }),
+    effect_overrides: Vec::new(),
+    value_reference_effect: None,
+    ingredient_rarities: Vec::new(),
+    ingredient_overrides: Vec::new(),
+    apparatus: Vec::new(),
+    game_settings: Vec::new(),
 };
 
 fn command_init(args: &ArgMatches) -> Result<(), String> {
-    let balance = match args.get_one::<String>("type").unwrap().as_ref() {
-        "original" => &ORIGINAL,
-        "recommended" => &RECOMMENDED,
-        _ => unreachable!()
+    let mut base_costs = HashMap::new();
+    if let Some(cfg) = args.get_one::<OsString>("config") {
+        let code_page = match args.get_one::<String>("code_page").unwrap().as_ref() {
+            "en" => CodePage::English,
+            "ru" => CodePage::Russian,
+            _ => unreachable!()
+        };
+        for file in game_files(Path::new(cfg), config_format(args), None, false)?.plugins {
+            collect_base_costs(&file, &mut base_costs, code_page)?;
+        }
+    }
+    let formulas;
+    let balance = if let Some(source) = args.get_one::<OsString>("formulas") {
+        formulas = read_balance(Path::new(source), &base_costs)?;
+        &formulas
+    } else {
+        match args.get_one::<String>("type").unwrap().as_ref() {
+            "original" => &ORIGINAL,
+            "recommended" => &RECOMMENDED,
+            _ => unreachable!()
+        }
     };
     let output = Path::new(args.get_one::<OsString>("output").unwrap());
     {
@@ -933,8 +2377,34 @@ fn command_init(args: &ArgMatches) -> Result<(), String> {
     Ok(())
 }
 
-const MW_INI: &[u8] = "Morrowind.ini".as_bytes();
-const MW_CFG: &[u8] = "openmw.cfg".as_bytes();
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum ConfigFormat {
+    Ini,
+    Cfg,
+}
+
+fn config_format(args: &ArgMatches) -> Option<ConfigFormat> {
+    args.get_one::<String>("config_format").map(|x| match x.as_ref() {
+        "ini" => ConfigFormat::Ini,
+        "cfg" => ConfigFormat::Cfg,
+        _ => unreachable!()
+    })
+}
+
+fn detect_config_format(cfg: &Path) -> Result<ConfigFormat, String> {
+    let mut text = Vec::new();
+    File::open(cfg).and_then(|mut x| x.read_to_end(&mut text)).map_err(|x| format!("'{}': {}.", cfg.display(), x))?;
+    for line in text.split(|&x| x == b'\n') {
+        let line = line.trim_ascii();
+        if line.is_empty() || line.starts_with(b"#") || line.starts_with(b";") { continue; }
+        if line.starts_with(b"[") { return Ok(ConfigFormat::Ini); }
+        if line.contains(&b'=') { return Ok(ConfigFormat::Cfg); }
+    }
+    Err(format!(
+        "'{}': unknown config file format, supported files are 'Morrowind.ini', and 'openmw.cfg' (use --config-format).",
+        cfg.display()
+    ))
+}
 
 fn command_scan(args: &ArgMatches) -> Result<(), String> {
     let code_page = match args.get_one::<String>("code_page").unwrap().as_ref() {
@@ -942,21 +2412,41 @@ fn command_scan(args: &ArgMatches) -> Result<(), String> {
         "ru" => CodePage::Russian,
         _ => unreachable!()
     };
-    let cfg = Path::new(args.get_one::<OsString>("CONFIG FILE").unwrap());
-    let cfg = match unsafe { transmute(cfg.file_name()) } {
-        Some(MW_INI) => parse_ini(cfg),
-        Some(MW_CFG) => parse_cfg(cfg),
-        _ => return Err("Unknown config file, supported files are 'Morrowind.ini', and 'openmw.cfg'.".into()),
-    }?;
-    let mut potions = HashMap::new();
+    let groundcover = args.get_flag("groundcover");
+    let game_files = if let Some(data) = args.get_many::<OsString>("data") {
+        let file_names = if let Some(load_order) = args.get_one::<OsString>("load_order") {
+            parse_load_order(Path::new(load_order))?.into_iter().map(OsString::from).collect()
+        } else {
+            args.get_many::<OsString>("plugin").unwrap().cloned().collect()
+        };
+        resolve_game_files(Config {
+            data_folders: data.map(PathBuf::from).collect(),
+            file_names,
+            groundcover: Vec::new(),
+            archives: Vec::new(),
+            missing: Vec::new(),
+        }, groundcover)?
+    } else {
+        let load_order = args.get_one::<OsString>("load_order").map(Path::new);
+        game_files(&config_file(args)?, config_format(args), load_order, groundcover)?
+    };
+    let mut items = Items::default();
     let mut max_time = None;
-    for file_name in cfg.file_names {
-        let file = cfg.data_folders.iter().rev()
-            .map(|x| x.join(&file_name))
-            .find(|x| fs::metadata(x).ok().map_or(false, |x| x.is_file()))
-            .ok_or_else(|| format!("'{}' not found", file_name.to_string_lossy()))?
-        ;
-        if collect_potions(&file, &mut potions, code_page, true)? {
+    for name in game_files.missing.iter().chain(&game_files.missing_archives) {
+        println!("Skipped '{}': file not found.", name.to_string_lossy());
+    }
+    for name in game_files.other_content {
+        println!("Skipped '{}': not a plugin file.", name.to_string_lossy());
+    }
+    for file in game_files.plugins {
+        let plugins = items.plugins.len();
+        let has_items = collect_potions(&file, &mut items, code_page, true)?;
+        if items.plugins.len() == plugins {
+            println!("Skipped '{}': balance plugin.", file.display());
+            continue;
+        }
+        println!("Scanned '{}'.", file.display());
+        if has_items {
             let metadata = fs::metadata(file).map_err(|x| x.to_string())?;
             let time = FileTime::from_last_modification_time(&metadata);
             if max_time.map_or(true, |max_time| time > max_time) {
@@ -965,16 +2455,228 @@ fn command_scan(args: &ArgMatches) -> Result<(), String> {
         }
     }
     let Some(max_time) = max_time else { return Err("Potions not found.".into()); };
+    items.masters = items.winning_plugins()?;
     let max_time = max_time.unix_seconds();
     if i64::MAX - max_time < 120 { return Err("File is too new: time limit exceeded.".into()); }
     let output_time = FileTime::from_unix_time(max_time + 120, 0);
     let output = Path::new(args.get_one::<OsString>("output").unwrap());
-    write_potions(output, potions, output_time, code_page)
+    write_potions(output, items, output_time, code_page)
+}
+
+fn command_order(args: &ArgMatches) -> Result<(), String> {
+    let code_page = match args.get_one::<String>("code_page").unwrap().as_ref() {
+        "en" => CodePage::English,
+        "ru" => CodePage::Russian,
+        _ => unreachable!()
+    };
+    let load_order = args.get_one::<OsString>("load_order").map(Path::new);
+    let game_files = game_files(&config_file(args)?, config_format(args), load_order, args.get_flag("groundcover"))?;
+    println!("Load order:");
+    for (index, file) in game_files.plugins.iter().enumerate() {
+        println!("{index:>4} {}", file.display());
+    }
+    if !game_files.other_content.is_empty() {
+        println!("Skipped content:");
+        for name in &game_files.other_content {
+            println!("     {}", name.to_string_lossy());
+        }
+    }
+    if !game_files.missing.is_empty() {
+        println!("Missing content:");
+        for name in &game_files.missing {
+            println!("     {}", name.to_string_lossy());
+        }
+    }
+    if !game_files.archives.is_empty() {
+        println!("Archives:");
+        for file in &game_files.archives {
+            println!("     {}", file.display());
+        }
+    }
+    if !game_files.missing_archives.is_empty() {
+        println!("Missing archives:");
+        for name in &game_files.missing_archives {
+            println!("     {}", name.to_string_lossy());
+        }
+    }
+    let mut chains = BTreeMap::<String, Vec<usize>>::new();
+    for (index, file) in game_files.plugins.iter().enumerate() {
+        let mut items = Items::default();
+        collect_potions(file, &mut items, code_page, false)?;
+        for id in items.potions.into_keys() {
+            chains.entry(id).or_default().push(index);
+        }
+    }
+    println!("Potions:");
+    for (id, chain) in chains {
+        let name = |index: &usize| game_files.plugins[*index].file_name().unwrap_or_default().to_string_lossy();
+        let chain = chain.iter().map(name).collect::<Vec<_>>();
+        println!("    {id}: {} (winner: {})", chain.join(" > "), chain.last().unwrap());
+    }
+    Ok(())
+}
+
+fn command_locate() -> Result<(), String> {
+    let configs = locate_configs();
+    if configs.is_empty() { return Err("Game config not found.".into()); }
+    for (source, cfg) in configs {
+        println!("{} ({source})", cfg.display());
+    }
+    Ok(())
+}
+
+fn config_file(args: &ArgMatches) -> Result<PathBuf, String> {
+    if let Some(cfg) = args.get_one::<OsString>("CONFIG FILE") {
+        return Ok(PathBuf::from(cfg));
+    }
+    let (_, cfg) = locate_configs().into_iter().next()
+        .ok_or("Game config not found, please specify <CONFIG FILE>.")?;
+    println!("Using '{}'.", cfg.display());
+    Ok(cfg)
+}
+
+fn locate_configs() -> Vec<(&'static str, PathBuf)> {
+    let mut candidates = Vec::new();
+    if let Some(dir) = openmw_user_config_dir() {
+        candidates.push(("OpenMW", dir.join("openmw.cfg")));
+    }
+    if let Some(home) = home_dir() {
+        candidates.push(("OpenMW", home.join(".config/openmw/openmw.cfg")));
+        candidates.push(("OpenMW Flatpak", home.join(".var/app/org.openmw.OpenMW/config/openmw/openmw.cfg")));
+    }
+    for library in steam_libraries() {
+        candidates.push(("Steam", library.join("steamapps/common/Morrowind/Morrowind.ini")));
+        let Ok(prefixes) = fs::read_dir(library.join("steamapps/compatdata")) else { continue; };
+        for prefix in prefixes.flatten() {
+            candidates.extend(morrowind_in_prefix(&prefix.path().join("pfx")).map(|x| ("Proton", x)));
+        }
+    }
+    let mut prefixes = env::var_os("WINEPREFIX").map(PathBuf::from).into_iter().collect::<Vec<_>>();
+    prefixes.extend(home_dir().map(|x| x.join(".wine")));
+    for prefix in prefixes {
+        candidates.extend(morrowind_in_prefix(&prefix).map(|x| ("Wine", x)));
+    }
+    if cfg!(windows) {
+        candidates.extend(morrowind_in_prefix(Path::new("C:\\")).map(|x| ("Windows", x)));
+    }
+    let mut found = HashSet::new();
+    candidates.into_iter()
+        .filter(|(_, x)| fs::metadata(x).is_ok_and(|x| x.is_file()))
+        .filter(|(_, x)| found.insert(fs::canonicalize(x).unwrap_or_else(|_| x.clone())))
+        .collect()
+}
+
+fn morrowind_in_prefix(prefix: &Path) -> impl Iterator<Item=PathBuf> {
+    let drive = if cfg!(windows) { prefix.to_path_buf() } else { prefix.join("drive_c") };
+    [
+        "Program Files (x86)/Bethesda Softworks/Morrowind",
+        "Program Files/Bethesda Softworks/Morrowind",
+        "Program Files (x86)/Steam/steamapps/common/Morrowind",
+        "GOG Games/Morrowind",
+    ].into_iter().map(move |x| drive.join(x).join("Morrowind.ini"))
+}
+
+fn steam_libraries() -> Vec<PathBuf> {
+    let mut roots = Vec::new();
+    if cfg!(windows) {
+        roots.push(PathBuf::from("C:\\Program Files (x86)\\Steam"));
+    } else if let Some(home) = home_dir() {
+        roots.push(home.join(".steam/steam"));
+        roots.push(home.join(".local/share/Steam"));
+        roots.push(home.join(".var/app/com.valvesoftware.Steam/.local/share/Steam"));
+        roots.push(home.join("Library/Application Support/Steam"));
+    }
+    let mut libraries = Vec::new();
+    for root in roots {
+        if !fs::metadata(&root).is_ok_and(|x| x.is_dir()) { continue; }
+        libraries.push(root.clone());
+        let Ok(vdf) = fs::read_to_string(root.join("steamapps/libraryfolders.vdf")) else { continue; };
+        for line in vdf.lines() {
+            let mut values = line.split('"').skip(1).step_by(2);
+            if values.next() != Some("path") { continue; }
+            let Some(path) = values.next() else { continue; };
+            libraries.push(PathBuf::from(path.replace("\\\\", "\\")));
+        }
+    }
+    libraries
+}
+
+fn game_files(
+    cfg: &Path,
+    format: Option<ConfigFormat>,
+    load_order: Option<&Path>,
+    groundcover: bool
+) -> Result<GameFiles, String> {
+    let format = if let Some(format) = format { format } else { detect_config_format(cfg)? };
+    let cfg = match format {
+        ConfigFormat::Ini => parse_ini(cfg, load_order),
+        ConfigFormat::Cfg if load_order.is_some() => {
+            return Err("Load order file can be used only with Morrowind.ini, openmw.cfg defines load order itself.".into());
+        },
+        ConfigFormat::Cfg => parse_cfg(cfg),
+    }?;
+    resolve_game_files(cfg, groundcover)
+}
+
+fn resolve_game_files(cfg: Config, groundcover: bool) -> Result<GameFiles, String> {
+    let mut file_names = cfg.file_names;
+    if groundcover {
+        file_names.extend(cfg.groundcover);
+    }
+    let mut plugins = Vec::with_capacity(file_names.len());
+    let mut other_content = Vec::new();
+    let mut missing = cfg.missing;
+    for file_name in file_names {
+        if content_kind(Path::new(&file_name)) == ContentKind::Other {
+            other_content.push(file_name);
+            continue;
+        }
+        match find_in_data_folder(&cfg.data_folders, &file_name) {
+            Some(file) => plugins.push(file),
+            None => missing.push(file_name),
+        }
+    }
+    let mut archives = Vec::with_capacity(cfg.archives.len());
+    let mut missing_archives = Vec::new();
+    for file_name in cfg.archives {
+        match find_in_data_folder(&cfg.data_folders, &file_name) {
+            Some(file) => archives.push(file),
+            None => missing_archives.push(file_name),
+        }
+    }
+    Ok(GameFiles {
+        plugins,
+        other_content,
+        missing,
+        archives,
+        missing_archives,
+    })
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd)]
+enum ContentKind {
+    Master,
+    Plugin,
+    Other,
+}
+
+fn content_kind(path: &Path) -> ContentKind {
+    match path.extension().and_then(|x| x.to_str()).map(|x| x.to_lowercase()).as_deref() {
+        Some("esm") | Some("omwgame") => ContentKind::Master,
+        Some("esp") | Some("omwaddon") => ContentKind::Plugin,
+        _ => ContentKind::Other,
+    }
+}
+
+fn find_in_data_folder(data_folders: &[PathBuf], file_name: &OsString) -> Option<PathBuf> {
+    data_folders.iter().rev()
+        .map(|x| x.join(file_name))
+        .find(|x| fs::metadata(x).ok().map_or(false, |x| x.is_file()))
 }
 
 fn write_potions(
     output: &Path,
-    potions: HashMap<String, Record>,
+    items: Items,
     time: FileTime,
     code_page: CodePage
 ) -> Result<(), String> {
@@ -992,7 +2694,14 @@ fn write_potions(
             }))
         ]
     });
-    records.extend(potions.into_values());
+    for (name, size) in items.masters {
+        records[0].fields.push((MAST, Field::StringZ(name.into())));
+        records[0].fields.push((DATA, Field::I64(size as i64)));
+    }
+    records.extend(items.game_settings);
+    records.extend(items.potions.into_values());
+    records.extend(items.ingredients.into_values());
+    records.extend(items.apparatus.into_values());
     let records_count = (records.len() - 1) as u32;
     if let Field::FileMetadata(f) = &mut records[0].fields[0].1 {
         f.records = records_count;
@@ -1009,9 +2718,127 @@ fn write_potions(
     Ok(())
 }
 
+fn patch_plugin(
+    target: &Path,
+    mut items: Items,
+    excluded: &HashSet<String>,
+    time: FileTime,
+    code_page: CodePage
+) -> Result<(), String> {
+    let mut bytes = Vec::new();
+    File::open(target).and_then(|mut x| x.read_to_end(&mut bytes)).map_err(|x| x.to_string())?;
+    let mut chunks = Vec::new();
+    let mut rest = &bytes[..];
+    while !rest.is_empty() {
+        if rest.len() < 16 { return Err(format!("'{}': invalid file.", target.display())); }
+        let size = 16 + u32::from_le_bytes(rest[4 .. 8].try_into().unwrap()) as usize;
+        if rest.len() < size { return Err(format!("'{}': invalid file.", target.display())); }
+        chunks.push(&rest[.. size]);
+        rest = &rest[size ..];
+    }
+    let Some((header, chunks)) = chunks.split_first() else {
+        return Err(format!("'{}': invalid file.", target.display()));
+    };
+    let mut header = read_record(header, code_page)
+        .filter(|x| x.tag == TES3)
+        .ok_or_else(|| format!("'{}': invalid file.", target.display()))?;
+    let mut game_settings = items.game_settings.into_iter()
+        .map(|record| (game_setting_name(&record).unwrap_or_default().to_lowercase(), record))
+        .collect::<BTreeMap<_, _>>();
+    let keys = chunks.iter().map(|chunk| {
+        let tag = Tag::from(u32::from_le_bytes(chunk[.. 4].try_into().unwrap()));
+        let id = match tag {
+            ALCH | INGR | APPA => read_record(chunk, code_page).and_then(|x|
+                x.fields.iter().find(|(tag, _)| *tag == NAME).and_then(|x|
+                    if let Field::StringZ(id) = &x.1 { Some(id.string.to_uppercase()) } else { None }
+                )
+            ),
+            GMST => read_record(chunk, code_page).and_then(|x| game_setting_name(&x).map(|x| x.to_lowercase())),
+            _ => None,
+        };
+        (tag, id)
+    }).collect::<Vec<_>>();
+    let last = keys.iter().enumerate()
+        .filter_map(|(index, (tag, id))| id.as_ref().map(|id| ((*tag, id), index)))
+        .collect::<HashMap<_, _>>();
+    let mut records = Vec::with_capacity(chunks.len());
+    for (index, (&chunk, (tag, id))) in chunks.iter().zip(&keys).enumerate() {
+        let Some(id) = id else {
+            records.push(chunk.to_vec());
+            continue;
+        };
+        if *tag == ALCH && excluded.contains(id) { continue; }
+        let map = match *tag {
+            ALCH => &mut items.potions,
+            INGR => &mut items.ingredients,
+            APPA => &mut items.apparatus,
+            _ => &mut game_settings,
+        };
+        let record = if last[&(*tag, id)] == index { map.remove(id) } else { None };
+        match record {
+            Some(record) => records.push(record_bytes(&record, code_page)?),
+            None => records.push(chunk.to_vec()),
+        }
+    }
+    let new_records = game_settings.into_values()
+        .chain(items.potions.into_values())
+        .chain(items.ingredients.into_values())
+        .chain(items.apparatus.into_values())
+        .map(|x| record_bytes(&x, code_page))
+        .collect::<Result<Vec<_>, _>>()?;
+    records.splice(0 .. 0, new_records);
+    if let Some((_, Field::FileMetadata(f))) = header.fields.first_mut() {
+        f.records = records.len() as u32;
+    } else {
+        return Err(format!("'{}': invalid file.", target.display()));
+    }
+    {
+        let mut output = BufWriter::new(File::create(target).map_err(|e| e.to_string())?);
+        output.write_all(&record_bytes(&header, code_page)?).map_err(|e| e.to_string())?;
+        for record in records {
+            output.write_all(&record).map_err(|e| e.to_string())?;
+        }
+        output.flush().map_err(|e| e.to_string())?;
+    }
+    set_file_mtime(target, time).map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+fn read_record(bytes: &[u8], code_page: CodePage) -> Option<Record> {
+    let mut bytes = bytes;
+    Records::new(code_page, RecordReadMode::Lenient, false, 0, &mut bytes).next()?.ok()
+}
+
+fn record_bytes(record: &Record, code_page: CodePage) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+    code::serialize_into(
+        &ValueWithSeed(record, RecordSerde { code_page: Some(code_page), omwsave: false }), &mut bytes, true
+    ).map_err(|e| e.to_string())?;
+    Ok(bytes)
+}
+
+fn game_setting_name(record: &Record) -> Option<&str> {
+    record.fields.iter().find(|(tag, _)| *tag == NAME).and_then(|x| match &x.1 {
+        Field::String(name) => Some(name.as_str()),
+        Field::StringZ(name) => Some(name.string.as_str()),
+        _ => None,
+    })
+}
+
 struct Config {
     data_folders: Vec<PathBuf>,
     file_names: Vec<OsString>,
+    groundcover: Vec<OsString>,
+    archives: Vec<OsString>,
+    missing: Vec<OsString>,
+}
+
+struct GameFiles {
+    plugins: Vec<PathBuf>,
+    other_content: Vec<OsString>,
+    missing: Vec<OsString>,
+    archives: Vec<PathBuf>,
+    missing_archives: Vec<OsString>,
 }
 
 fn eof(reader: &mut dyn BufRead) -> io::Result<bool> {
@@ -1028,68 +2855,262 @@ fn parse_cfg(mw_cfg: &Path) -> Result<Config, String> {
     let mut config = Config {
         data_folders: Vec::new(),
         file_names: Vec::new(),
+        groundcover: Vec::new(),
+        archives: Vec::new(),
+        missing: Vec::new(),
     };
-    let file = File::open(mw_cfg).map_err(|x| x.to_string())?;
+    let local = mw_cfg.parent().unwrap_or(Path::new("")).to_path_buf();
+    let mut data_local = None;
+    let mut visited = HashSet::new();
+    parse_cfg_file(mw_cfg, &local, &mut config, &mut data_local, &mut visited)?;
+    if let Some(data_local) = data_local {
+        config.data_folders.push(data_local);
+    }
+    Ok(config)
+}
+
+fn parse_cfg_file(
+    mw_cfg: &Path,
+    local: &Path,
+    config: &mut Config,
+    data_local: &mut Option<PathBuf>,
+    visited: &mut HashSet<PathBuf>
+) -> Result<(), String> {
+    if !visited.insert(fs::canonicalize(mw_cfg).unwrap_or_else(|_| mw_cfg.to_path_buf())) { return Ok(()); }
+    let dir = mw_cfg.parent().unwrap_or(Path::new(""));
+    let mut sub_configs = Vec::new();
+    let mut file_config = Config {
+        data_folders: Vec::new(),
+        file_names: Vec::new(),
+        groundcover: Vec::new(),
+        archives: Vec::new(),
+        missing: Vec::new(),
+    };
+    let mut file_data_local = None;
+    let mut replace = HashSet::new();
+    let file = File::open(mw_cfg).map_err(|x| format!("'{}': {}.", mw_cfg.display(), x))?;
     let mut reader = BufReader::new(file);
     loop {
         if eof(&mut reader).map_err(|x| x.to_string())? { break; }
         let mut line = Vec::new();
         reader.read_until(b'\n', &mut line).map_err(|x| x.to_string())?;
-        let line = if line.ends_with(b"\r\n") {
-            &line[.. line.len() - 2]
-        } else if line.ends_with(b"\n") {
-            &line[.. line.len() - 1]
-        } else {
-            &line[..]
-        };
-        let Some(eq) = line.iter().enumerate().find(|(_, &b)| b == b'=') else { continue; };
-        let key = &line[.. eq.0];
-        let value = &line[eq.0 + 1 ..];
-        match key {
-            b"data" => {
-                let value = str::from_utf8(value).map_err(|x| x.to_string())?;
-                let value = if value.len() >= 2 && value.starts_with('\"') && value.ends_with('\"') {
-                    &value[1 .. value.len() - 1]
-                } else {
-                    value
-                };
-                config.data_folders.push(PathBuf::from_str(value).map_err(|x| x.to_string())?);
-            },
-            b"content" => {
-                let value = str::from_utf8(value).map_err(|x| x.to_string())?;
-                config.file_names.push(OsString::from_str(value).map_err(|x| x.to_string())?);
-            },
+        let line = str::from_utf8(&line).map_err(|_| format!("'{}': invalid file.", mw_cfg.display()))?.trim();
+        if line.is_empty() || line.starts_with('#') { continue; }
+        let Some((key, value)) = line.split_once('=') else { continue; };
+        let value = value.trim();
+        match key.trim() {
+            "data" => file_config.data_folders.push(cfg_path(value, dir, local)?),
+            "data-local" => file_data_local = Some(cfg_path(value, dir, local)?),
+            "content" => file_config.file_names.push(OsString::from(value)),
+            "groundcover" => file_config.groundcover.push(OsString::from(value)),
+            "fallback-archive" => file_config.archives.push(OsString::from(value)),
+            "config" => sub_configs.push(cfg_path(value, dir, local)?),
+            "replace" => { replace.insert(value.to_string()); },
             _ => { },
         }
     }
-    Ok(config)
+    for key in replace {
+        match key.as_str() {
+            "data" => config.data_folders.clear(),
+            "data-local" => *data_local = None,
+            "content" => config.file_names.clear(),
+            "groundcover" => config.groundcover.clear(),
+            "fallback-archive" => config.archives.clear(),
+            _ => { },
+        }
+    }
+    config.data_folders.extend(file_config.data_folders);
+    config.file_names.extend(file_config.file_names);
+    config.groundcover.extend(file_config.groundcover);
+    config.archives.extend(file_config.archives);
+    if file_data_local.is_some() {
+        *data_local = file_data_local;
+    }
+    for sub_config in sub_configs {
+        let sub_config = sub_config.join("openmw.cfg");
+        if fs::metadata(&sub_config).is_ok_and(|x| x.is_file()) {
+            parse_cfg_file(&sub_config, local, config, data_local, visited)?;
+        }
+    }
+    Ok(())
+}
+
+fn cfg_path(value: &str, dir: &Path, local: &Path) -> Result<PathBuf, String> {
+    let value = if let Some(quoted) = value.strip_prefix('"') {
+        let mut unquoted = String::with_capacity(quoted.len());
+        let mut chars = quoted.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '&' => if let Some(c) = chars.next() { unquoted.push(c); },
+                '"' => break,
+                c => unquoted.push(c),
+            }
+        }
+        unquoted
+    } else {
+        value.to_string()
+    };
+    let path = if let Some(token) = value.strip_prefix('?') {
+        let Some((token, rest)) = token.split_once('?') else {
+            return Err(format!("'{}': invalid path.", value));
+        };
+        let base = match token {
+            "local" => Some(local.to_path_buf()),
+            "userconfig" => openmw_user_config_dir(),
+            "userdata" => openmw_user_data_dir(),
+            "global" => openmw_global_data_dir(),
+            _ => return Err(format!("'{}': unknown path token '?{}?'.", value, token)),
+        }.ok_or_else(|| format!("'{}': cannot resolve path token '?{}?'.", value, token))?;
+        base.join(rest.trim_start_matches(['/', '\\']))
+    } else {
+        PathBuf::from(value)
+    };
+    Ok(if path.is_relative() { dir.join(path) } else { path })
+}
+
+fn home_dir() -> Option<PathBuf> {
+    env::var_os(if cfg!(windows) { "USERPROFILE" } else { "HOME" }).map(PathBuf::from)
 }
 
-fn parse_ini(mw_ini: &Path) -> Result<Config, String> {
+fn xdg_dir(var: &str, default: &str) -> Option<PathBuf> {
+    env::var_os(var).filter(|x| !x.is_empty()).map(PathBuf::from).or_else(|| home_dir().map(|x| x.join(default)))
+}
+
+fn openmw_user_config_dir() -> Option<PathBuf> {
+    if cfg!(windows) {
+        home_dir().map(|x| x.join("Documents").join("My Games").join("OpenMW"))
+    } else if cfg!(target_os = "macos") {
+        home_dir().map(|x| x.join("Library/Preferences/openmw"))
+    } else {
+        xdg_dir("XDG_CONFIG_HOME", ".config").map(|x| x.join("openmw"))
+    }
+}
+
+fn openmw_user_data_dir() -> Option<PathBuf> {
+    if cfg!(windows) {
+        home_dir().map(|x| x.join("Documents").join("My Games").join("OpenMW"))
+    } else if cfg!(target_os = "macos") {
+        home_dir().map(|x| x.join("Library/Application Support/openmw"))
+    } else {
+        xdg_dir("XDG_DATA_HOME", ".local/share").map(|x| x.join("openmw"))
+    }
+}
+
+fn openmw_global_data_dir() -> Option<PathBuf> {
+    if cfg!(windows) {
+        None
+    } else if cfg!(target_os = "macos") {
+        Some(PathBuf::from("/Library/Application Support/openmw"))
+    } else {
+        Some(PathBuf::from("/usr/share/games/openmw"))
+    }
+}
+
+fn parse_ini(mw_ini: &Path, load_order: Option<&Path>) -> Result<Config, String> {
     let data_folder = mw_ini.with_file_name("Data Files");
     let mut ini = Vec::new();
     File::open(mw_ini).and_then(|mut x| x.read_to_end(&mut ini)).map_err(|x| x.to_string())?;
     let ini = WINDOWS_1251.decode(&ini, DecoderTrap::Strict).map_err(|x| x.to_string())?;
     let ini = Ini::load_from_str(&ini).map_err(|x| x.to_string())?;
     let game_files_section = ini.section(Some("Game Files")).ok_or("The [Game Files] section is missing.")?;
+    let load_order = if let Some(load_order) = load_order { parse_load_order(load_order)? } else { Vec::new() };
     let mut game_files = Vec::with_capacity(game_files_section.len());
-    for (_, name) in game_files_section.iter() {
+    let mut missing = Vec::new();
+    let mut names = HashSet::new();
+    for (key, name) in game_files_section.iter() {
+        if !names.insert(name.to_lowercase()) { continue; }
+        let number = key.trim_start_matches(|x: char| !x.is_ascii_digit()).parse::<u32>().unwrap_or(u32::MAX);
         let path = data_folder.join(name);
-        let metadata = fs::metadata(path.as_path()).map_err(|x| x.to_string())?;
+        let Ok(metadata) = fs::metadata(path.as_path()) else {
+            missing.push(OsString::from(name));
+            continue;
+        };
         let time = FileTime::from_last_modification_time(&metadata);
-        game_files.push((name, path, time));
+        let position = load_order.iter().position(|x| x.eq_ignore_ascii_case(name)).unwrap_or(usize::MAX);
+        game_files.push(((content_kind(&path), position, time, number), name));
     }
-    game_files.sort_by_key(|x| x.2);
-    game_files.sort_by_key(|x| x.1.extension().and_then(|e| e.to_str()).map(|e| e.to_uppercase()));
+    game_files.sort_by_key(|x| x.0);
+    let archives = ini.section(Some("Archives"))
+        .map(|x| x.iter().map(|(_, name)| OsString::from(name)).collect())
+        .unwrap_or_default();
     Ok(Config {
         data_folders: vec![data_folder],
-        file_names: game_files.iter().map(|x| OsString::from(x.0)).collect()
+        file_names: game_files.iter().map(|x| OsString::from(x.1)).collect(),
+        groundcover: Vec::new(),
+        archives,
+        missing,
     })
 }
 
+fn parse_load_order(path: &Path) -> Result<Vec<String>, String> {
+    let mut text = Vec::new();
+    File::open(path).and_then(|mut x| x.read_to_end(&mut text)).map_err(|x| x.to_string())?;
+    let text = String::from_utf8(text).map_err(|_| format!("'{}': invalid file.", path.display()))?;
+    Ok(text.lines()
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty() && !x.starts_with('#'))
+        .map(|x| x.trim_start_matches('*').to_string())
+        .collect()
+    )
+}
+
+fn collect_base_costs(
+    path: &Path,
+    base_costs: &mut HashMap<EffectIndex, f32>,
+    code_page: CodePage,
+) -> Result<(), String> {
+    let mut file = File::open(path).map_err(|x| x.to_string())?;
+    let records = Records::new(code_page, RecordReadMode::Lenient, false, 0, &mut file);
+    for record in records {
+        let record = match record {
+            Err(error) => match error.source() {
+                Right(error) => return Err(format!("'{}': {}.", path.display(), error)),
+                Left(error) => if error.record_tag() == MGEF {
+                    return Err(format!("'{}': {}.", path.display(), error));
+                } else {
+                    continue;
+                }
+            },
+            Ok(record) => record
+        };
+        if record.tag != MGEF { continue; }
+        let Some((_, Field::EffectIndex(effect))) = record.fields.iter().find(|(tag, _)| *tag == INDX) else {
+            return Err(format!("'{}': missing INDX field in MGEF record.", path.display()));
+        };
+        let Some((_, Field::EffectMetadata(metadata))) = record.fields.iter().find(|(tag, _)| *tag == MEDT) else {
+            return Err(format!("'{}': missing MEDT field in MGEF record.", path.display()));
+        };
+        base_costs.insert(*effect, metadata.base_cost);
+    }
+    Ok(())
+}
+
+#[derive(Default)]
+struct Items {
+    potions: BTreeMap<String, Record>,
+    ingredients: BTreeMap<String, Record>,
+    apparatus: BTreeMap<String, Record>,
+    game_settings: Vec<Record>,
+    masters: Vec<(String, u64)>,
+    plugins: Vec<PathBuf>,
+    origins: HashMap<(Tag, String), usize>,
+}
+
+impl Items {
+    fn winning_plugins(&self) -> Result<Vec<(String, u64)>, String> {
+        let origins = self.origins.values().copied().collect::<HashSet<_>>();
+        self.plugins.iter().enumerate().filter(|(index, _)| origins.contains(index)).map(|(_, path)| {
+            let name = path.file_name().and_then(|x| x.to_str())
+                .ok_or_else(|| format!("'{}': invalid file name.", path.display()))?;
+            let size = fs::metadata(path).map_err(|x| x.to_string())?.len();
+            Ok((name.to_string(), size))
+        }).collect()
+    }
+}
+
 fn collect_potions(
     path: &Path,
-    potions: &mut HashMap<String, Record>,
+    items: &mut Items,
     code_page: CodePage,
     skip_balance_plugin: bool,
 ) -> Result<bool, String> {
@@ -1103,12 +3124,14 @@ fn collect_potions(
     } else {
         return Err(format!("'{}': invalid file.", path.display()));
     }
-    let mut has_potions = false;
+    let origin = items.plugins.len();
+    items.plugins.push(path.to_path_buf());
+    let mut has_items = false;
     for record in records {
         let record = match record {
             Err(error) => match error.source() {
                 Right(error) => return Err(format!("'{}': {}.", path.display(), error)),
-                Left(error) => if error.record_tag() == ALCH {
+                Left(error) => if [ALCH, INGR, APPA].contains(&error.record_tag()) {
                     return Err(format!("'{}': {}.", path.display(), error));
                 } else {
                     continue;
@@ -1116,15 +3139,139 @@ fn collect_potions(
             },
             Ok(record) => record
         };
-        if record.tag != ALCH { continue; }
+        let map = match record.tag {
+            ALCH => &mut items.potions,
+            INGR => &mut items.ingredients,
+            APPA => &mut items.apparatus,
+            _ => continue,
+        };
         let id = if let Field::StringZ(ref id) = record.fields.iter().find(|(tag, _)| *tag == NAME)
-            .ok_or_else(|| format!("'{}': missing NAME field in ALCH record.", path.display()))?.1 {
+            .ok_or_else(|| format!("'{}': missing NAME field in {} record.", path.display(), record.tag))?.1 {
             id.string.to_uppercase()
         } else {
             panic!()
         };
-        potions.insert(id, record);
-        has_potions = true;
+        items.origins.insert((record.tag, id.clone()), origin);
+        map.insert(id, record);
+        has_items = true;
+    }
+    Ok(has_items)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn potion_record(id: &str, name: &str) -> Record {
+        Record {
+            tag: ALCH,
+            flags: RecordFlags::empty(),
+            fields: vec![(NAME, Field::StringZ(id.into())), (FNAM, Field::StringZ(name.into()))]
+        }
+    }
+
+    fn plugin_chunks(bytes: &[u8]) -> Vec<&[u8]> {
+        let mut chunks = Vec::new();
+        let mut rest = bytes;
+        while !rest.is_empty() {
+            let size = 16 + u32::from_le_bytes(rest[4 .. 8].try_into().unwrap()) as usize;
+            chunks.push(&rest[.. size]);
+            rest = &rest[size ..];
+        }
+        chunks
+    }
+
+    fn eval(formula: &str) -> Result<f64, String> {
+        eval_formula(formula, FormulaVariables { tier: Some(2.0), base: None })
+    }
+
+    #[test]
+    fn formula_precedence() {
+        assert_eq!(eval("1 + 2 * 3"), Ok(7.0));
+        assert_eq!(eval("(1 + 2) * 3"), Ok(9.0));
+        assert_eq!(eval("10 - 4 - 3"), Ok(3.0));
+        assert_eq!(eval("12 / 3 / 2"), Ok(2.0));
+        assert_eq!(eval("2 * 3 ^ 2"), Ok(18.0));
+        assert_eq!(eval("5 * 2 ^ tier"), Ok(20.0));
+    }
+
+    #[test]
+    fn formula_power_is_right_associative() {
+        assert_eq!(eval("2 ^ 3 ^ 2"), Ok(512.0));
+        assert_eq!(eval("(2 ^ 3) ^ 2"), Ok(64.0));
+    }
+
+    #[test]
+    fn formula_error_positions() {
+        assert_eq!(eval("1 + * 2"), Err("number expected at position 5".into()));
+        assert_eq!(eval("1 +"), Err("unexpected end at position 4".into()));
+        assert_eq!(eval("(1 + 2"), Err("missing ')' at position 7".into()));
+        assert_eq!(eval("2 3"), Err("unexpected '3' at position 3".into()));
+        assert_eq!(eval("1 + foo"), Err("unknown variable 'foo' at position 5".into()));
+        assert!(eval("base * 2").unwrap_err().ends_with("at position 1"));
+    }
+
+    #[test]
+    fn patch_plugin_in_place() {
+        let code_page = CodePage::English;
+        let header = Record {
+            tag: TES3,
+            flags: RecordFlags::empty(),
+            fields: vec![(HEDR, Field::FileMetadata(FileMetadata {
+                version: 1067869798,
+                file_type: FileType::ESP,
+                author: Right("author".to_string()),
+                description: Right(vec!["Hand-edited.".into()]),
+                records: 5
+            }))]
+        };
+        let unrecognized = [b"XXXX".as_slice(), &4u32.to_le_bytes(), &[0; 8], b"data"].concat();
+        let first = record_bytes(&potion_record("p_dup", "First"), code_page).unwrap();
+        let second = record_bytes(&potion_record("p_dup", "Second"), code_page).unwrap();
+        let bytes = [
+            record_bytes(&header, code_page).unwrap(),
+            first.clone(),
+            unrecognized.clone(),
+            second,
+            record_bytes(&game_setting_record("fFoo", &GameSetting::Float(1.0)), code_page).unwrap(),
+            record_bytes(&potion_record("p_ex", "Excluded"), code_page).unwrap(),
+        ].concat();
+        let target = env::temp_dir().join(format!("espb-patch-plugin-{}.esp", std::process::id()));
+        fs::write(&target, bytes).unwrap();
+        let mut items = Items::default();
+        items.potions.insert("P_DUP".into(), potion_record("p_dup", "Patched"));
+        items.game_settings.push(game_setting_record("FFOO", &GameSetting::Float(2.0)));
+        items.game_settings.push(game_setting_record("iNew", &GameSetting::Int(3)));
+        let excluded = ["P_EX".to_string()].into_iter().collect();
+        let result = patch_plugin(&target, items, &excluded, FileTime::from_unix_time(0, 0), code_page);
+        let bytes = fs::read(&target).unwrap();
+        fs::remove_file(&target).unwrap();
+        result.unwrap();
+        let chunks = plugin_chunks(&bytes);
+        let Some((_, Field::FileMetadata(metadata))) = read_record(chunks[0], code_page).unwrap().fields.first().cloned() else {
+            panic!()
+        };
+        assert_eq!(metadata.records, 5);
+        assert_eq!(metadata.author, Right("author".to_string()));
+        assert_eq!(metadata.description, Right(vec!["Hand-edited.".to_string()]));
+        assert_eq!(&chunks[1 ..], &[
+            record_bytes(&game_setting_record("iNew", &GameSetting::Int(3)), code_page).unwrap().as_slice(),
+            &first,
+            &unrecognized,
+            &record_bytes(&potion_record("p_dup", "Patched"), code_page).unwrap(),
+            &record_bytes(&game_setting_record("FFOO", &GameSetting::Float(2.0)), code_page).unwrap(),
+        ]);
+    }
+
+    #[test]
+    fn glob_backtracking() {
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"P_*_S", b"P_RESTORE_HEALTH_S"));
+        assert!(glob_match(b"*_S*_S", b"P_S_X_S"));
+        assert!(glob_match(b"*AB?", b"AABABC"));
+        assert!(glob_match(b"A*B*C", b"AXBXBXC"));
+        assert!(!glob_match(b"A*B*C", b"AXBXBX"));
+        assert!(!glob_match(b"*_S", b"P_S_X"));
+        assert!(!glob_match(b"?", b""));
     }
-    Ok(has_potions)
 }